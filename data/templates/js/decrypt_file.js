@@ -0,0 +1,87 @@
+const encryptedPreview = document.getElementById("encrypted-preview");
+const decryptStatus = document.getElementById("decrypt-status");
+const decryptButton = document.getElementById("decrypt-button");
+const decryptedDownload = document.getElementById("decrypted-download");
+const decryptedPreview = document.getElementById("decrypted-preview");
+
+// Same limit as the preview of files that are not encrypted
+const MAX_TEXT_PREVIEW_LENGTH = 16384;
+
+// Import the base64url encoded key from the fragment of the link
+async function importKey() {
+	const encoded = window.location.hash.substring(1).replace(/-/g, "+").replace(/_/g, "/");
+	const raw = Uint8Array.from(atob(encoded), (c) => c.charCodeAt(0));
+	return crypto.subtle.importKey("raw", raw, { name: "AES-GCM" }, false, ["decrypt"]);
+}
+
+// Download the file and decrypt it, the first 12 bytes are the IV
+async function downloadDecrypted() {
+	const key = await importKey();
+	const response = await fetch(encryptedPreview.dataset.url);
+	if (!response.ok) {
+		throw Error(`Could not download the file: ${response.status}`);
+	}
+	const data = await response.arrayBuffer();
+	const plaintext = await crypto.subtle.decrypt(
+		{ name: "AES-GCM", iv: data.slice(0, 12) },
+		key,
+		data.slice(12),
+	);
+	return new Blob([plaintext], { type: encryptedPreview.dataset.contentType });
+}
+
+async function showDecrypted() {
+	decryptButton.hidden = true;
+	decryptStatus.textContent = "Decrypting...";
+	let blob;
+	try {
+		blob = await downloadDecrypted();
+	} catch (e) {
+		console.error(e);
+		decryptStatus.textContent = "Could not decrypt the file, is the link complete?";
+		return;
+	}
+	decryptStatus.textContent = "";
+
+	const url = URL.createObjectURL(blob);
+	decryptedDownload.href = url;
+	decryptedDownload.download = encryptedPreview.dataset.name;
+	decryptedDownload.hidden = false;
+
+	let preview;
+	if (blob.type.startsWith("image/")) {
+		preview = document.createElement("img");
+		preview.src = url;
+	} else if (blob.type.startsWith("text/")) {
+		let text = await blob.slice(0, MAX_TEXT_PREVIEW_LENGTH).text();
+		if (blob.size > MAX_TEXT_PREVIEW_LENGTH) {
+			text += "\n===============\n(abbreviated)";
+		}
+		preview = document.createElement("pre");
+		preview.classList.add("bg-secondary-subtle", "border-2", "font-monospace", "p-3");
+		preview.style.whiteSpace = "pre-wrap";
+		preview.textContent = text;
+	} else {
+		preview = document.createElement("object");
+		preview.type = blob.type;
+		preview.data = url;
+		preview.style.minWidth = "100%";
+		preview.style.height = "100%";
+		preview.textContent = "This preview didn't work :(";
+	}
+	preview.id = "preview-actual";
+	decryptedPreview.appendChild(preview);
+}
+
+if (!window.location.hash) {
+	decryptStatus.textContent = "The link has no key, the file can't be decrypted.";
+} else if (!window.crypto || !window.crypto.subtle) {
+	decryptStatus.textContent = "Decrypting needs a secure context (https).";
+} else if (encryptedPreview.dataset.showContent === "true") {
+	showDecrypted();
+} else {
+	// every download counts against the limit, so only download when asked to
+	decryptStatus.textContent = "This file can only be downloaded a limited number of times.";
+	decryptButton.hidden = false;
+	decryptButton.addEventListener("click", showDecrypted);
+}
@@ -0,0 +1,116 @@
+// for the list of the files of the user
+document.addEventListener('DOMContentLoaded', function() {
+	const rows = document.getElementById('fileRows');
+	const rowTemplate = document.getElementById('fileRowTemplate');
+	const status = document.getElementById('fileListStatus');
+	const pageInfo = document.getElementById('pageInfo');
+	const prevPage = document.getElementById('prevPage');
+	const nextPage = document.getElementById('nextPage');
+	const filterName = document.getElementById('filterName');
+	const filters = ['filterType', 'filterExpires', 'sortBy', 'sortOrder'].map(id => document.getElementById(id));
+	const perPage = 25;
+	let page = 1;
+
+	async function loadFiles() {
+		const params = new URLSearchParams({
+			page: page,
+			per_page: perPage,
+			sort: document.getElementById('sortBy').value,
+			order: document.getElementById('sortOrder').value,
+		});
+		if (filterName.value.trim()) {
+			params.append('name', filterName.value.trim());
+		}
+		if (document.getElementById('filterType').value) {
+			params.append('content_type', document.getElementById('filterType').value);
+		}
+		if (document.getElementById('filterExpires').value) {
+			params.append('expires_within', document.getElementById('filterExpires').value);
+		}
+
+		try {
+			const response = await fetch(`/api/v1/files?${params}`);
+			if (!response.ok) {
+				throw new Error(`HTTP error! status: ${response.status}`);
+			}
+			const list = await response.json();
+			const pages = Math.max(1, Math.ceil(list.total / list.per_page));
+			if (page > pages) {
+				// the last page became empty
+				page = pages;
+				return loadFiles();
+			}
+
+			rows.replaceChildren(...list.files.map(makeRow));
+			status.textContent = list.total == 0 ? 'No files found' : '';
+			pageInfo.textContent = `Page ${list.page} of ${pages}, ${list.total} files`;
+			prevPage.disabled = list.page <= 1;
+			nextPage.disabled = list.page >= pages;
+		} catch (error) {
+			console.error('Error loading files:', error);
+			status.textContent = 'Could not load your files. Please try again.';
+		}
+	}
+
+	// file names are set as text, never as html
+	function makeRow(file) {
+		const row = rowTemplate.content.firstElementChild.cloneNode(true);
+		const link = row.querySelector('.fileLink');
+		link.href = file.url_frontend;
+		link.textContent = file.name;
+		if (file.description) {
+			link.title = file.description;
+		}
+		row.querySelector('.fileSize').textContent = file.size_human;
+		row.querySelector('.fileType').textContent = file.content_type;
+		row.querySelector('.fileExpiration').textContent = new Date(file.time_expiration + 'Z').toLocaleString();
+
+		row.querySelector('.fileCopy').addEventListener('click', async function() {
+			const url = file.url_slug ?? file.url_frontend;
+			await navigator.clipboard.writeText(url);
+			this.textContent = 'Copied';
+			setTimeout(() => this.textContent = 'Copy link', 1500);
+		});
+
+		row.querySelector('.fileExtend').addEventListener('click', async function() {
+			const expiresIn = row.querySelector('.fileExtendChoice').value;
+			const response = await fetch(`/api/v1/file/${file.fid}`, {
+				method: 'PATCH',
+				headers: { 'Content-Type': 'application/json' },
+				body: JSON.stringify({ expires_in: expiresIn }),
+			});
+			if (!response.ok) {
+				alert(`Failed to extend "${file.name}": ${await response.text()}`);
+				return;
+			}
+			loadFiles();
+		});
+
+		row.querySelector('.fileDelete').addEventListener('click', async function() {
+			if (!confirm(`Are you sure you want to delete "${file.name}"? This action cannot be undone.`)) {
+				return;
+			}
+			this.disabled = true;
+			const response = await fetch(`/api/v1/file/${file.fid}`, { method: 'DELETE' });
+			if (!response.ok) {
+				alert(`Failed to delete "${file.name}". Please try again.`);
+				this.disabled = false;
+				return;
+			}
+			loadFiles();
+		});
+		return row;
+	}
+
+	prevPage.addEventListener('click', () => { page -= 1; loadFiles(); });
+	nextPage.addEventListener('click', () => { page += 1; loadFiles(); });
+	filters.forEach(filter => filter.addEventListener('change', () => { page = 1; loadFiles(); }));
+	let typing;
+	filterName.addEventListener('input', () => {
+		clearTimeout(typing);
+		typing = setTimeout(() => { page = 1; loadFiles(); }, 300);
+	});
+	document.getElementById('fileFilterForm').addEventListener('submit', e => e.preventDefault());
+
+	loadFiles();
+});
@@ -5,6 +5,12 @@ const fileInput = document.querySelector("#fileInput");
 const textInput = document.querySelector("#textInput");
 const fileTypeSelect = document.querySelector("#fileTypeSelect");
 const customFileName = document.querySelector("#customFileName");
+const expiresInSelect = document.querySelector("#expiresInSelect");
+const maxDownloadsSelect = document.querySelector("#maxDownloadsSelect");
+const filePasswordInput = document.querySelector("#filePasswordInput");
+const encryptCheckbox = document.querySelector("#encryptCheckbox");
+// only there for logged in users
+const slugInput = document.querySelector("#slugInput");
 const previewContainer = document.getElementById("preview-container");
 const textPreviewContainer = document.getElementById("text-preview-container");
 const formStatus = document.getElementById("formstatus");
@@ -19,6 +25,8 @@ async function sendData() {
 	// Create FormData object
 	const formData = new FormData();
 
+	let files;
+
 	// Check which tab is active
 	const isTextMode = textPane.classList.contains("show") && textPane.classList.contains("active");
 
@@ -42,17 +50,50 @@ async function sendData() {
 		});
 
 		// Add the file to FormData
-		formData.append("file", textFile);
+		files = [textFile];
 	} else {
-		// Handle regular file upload
-		const file = fileInput.files[0];
-		if (!file) {
+		// Handle regular file upload, several files become a collection
+		files = Array.from(fileInput.files);
+		if (!files.length) {
 			formStatus.innerHTML = "Please select a file to upload";
 			return;
 		}
+	}
+
+	// The key only ever goes into the fragment of the link, which browsers don't send to the server
+	let keyFragment = "";
+	if (encryptCheckbox.checked) {
+		if (files.length > 1) {
+			formStatus.innerHTML = "Only single files can be encrypted";
+			return;
+		}
+		if (!window.crypto || !window.crypto.subtle) {
+			formStatus.innerHTML = "Encryption needs a secure context (https)";
+			return;
+		}
+		formStatus.innerHTML = "Encrypting...";
+		const encrypted = await encryptFile(files[0]);
+		files = [encrypted.file];
+		keyFragment = "#" + encrypted.key;
+		formData.append("encrypted", "true");
+	}
+	for (const file of files) {
 		formData.append("file", file);
 	}
 
+	if (expiresInSelect.value) {
+		formData.append("expires_in", expiresInSelect.value);
+	}
+	if (maxDownloadsSelect.value) {
+		formData.append("max_downloads", maxDownloadsSelect.value);
+	}
+	if (filePasswordInput.value) {
+		formData.append("password", filePasswordInput.value);
+	}
+	if (slugInput && slugInput.value.trim()) {
+		formData.append("slug", slugInput.value.trim());
+	}
+
 	console.info("Trying the upload");
 	try {
 		const response = await fetch("/api/v1/file", {
@@ -65,13 +106,19 @@ async function sendData() {
 				"Not logged in and anonymous uploads are disabled by the administrator";
 			return;
 		}
+		if (response.status == 400 || response.status == 409 || response.status == 413 || response.status == 507) {
+			const error_stuff = await response.json();
+			formStatus.innerHTML = error_stuff.error;
+			return;
+		}
 		if (!response.ok) {
 			throw Error("Could not upload file");
 		}
 
 		response.json().then((response_stuff) => {
-			var url_frontend = response_stuff.url_frontend;
+			var url_frontend = response_stuff.url_slug ?? response_stuff.url_frontend;
 			if (url_frontend != null) {
+				url_frontend += keyFragment;
 				formOut.innerHTML = `View Upload: <a href=${url_frontend}>: ${url_frontend}</a>`;
 				window.location.replace(url_frontend);
 			}
@@ -82,6 +129,21 @@ async function sendData() {
 	}
 }
 
+// Encrypt a file with a new random AES-GCM key
+//
+// The encrypted file is the 12 byte IV followed by the ciphertext, and keeps the name of the original.
+// Returns it together with the key, encoded as base64url.
+async function encryptFile(file) {
+	const key = await crypto.subtle.generateKey({ name: "AES-GCM", length: 256 }, true, ["encrypt"]);
+	const iv = crypto.getRandomValues(new Uint8Array(12));
+	const ciphertext = await crypto.subtle.encrypt({ name: "AES-GCM", iv: iv }, key, await file.arrayBuffer());
+	const rawKey = new Uint8Array(await crypto.subtle.exportKey("raw", key));
+	return {
+		file: new File([iv, ciphertext], file.name, { type: "application/octet-stream" }),
+		key: btoa(String.fromCharCode(...rawKey)).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, ""),
+	};
+}
+
 // Get appropriate MIME type for file extension
 function getContentType(extension) {
 	const contentTypes = {
@@ -125,8 +187,8 @@ dropContainer.addEventListener("drop", handleDrop);
 
 // File input change handler
 fileInput.addEventListener("change", function(something) {
-	if (something.target.files[0]) {
-		makePreview(something.target.files[0]);
+	if (something.target.files.length) {
+		makePreview(something.target.files);
 	}
 });
 
@@ -151,13 +213,20 @@ function handleDrop(e) {
 		fileTabTrigger.show();
 
 		fileInput.files = files;
-		makePreview(files[0]);
+		makePreview(files);
 	}
 }
 
 // Make preview for uploaded files
-function makePreview(file) {
+function makePreview(files) {
 	console.log("processing file");
+	if (files.length > 1) {
+		const preview = document.createElement("p");
+		preview.textContent = `${files.length} files, they will be uploaded as a collection`;
+		showPreview(preview);
+		return;
+	}
+	const file = files[0];
 	const reader = new FileReader();
 	reader.readAsDataURL(file);
 
@@ -170,17 +239,22 @@ function makePreview(file) {
 			preview = document.createElement("p");
 			preview.textContent = "No preview for this file type";
 		}
-		preview.id = "preview-actual";
-		preview.classList.add("m-5");
+		showPreview(preview);
+	};
+}
 
-		// Remove the old preview if there is any
-		var maybe_old = document.getElementById("preview-actual");
-		if (maybe_old) {
-			previewContainer.removeChild(maybe_old);
-		}
+// Replace the file preview with `preview`
+function showPreview(preview) {
+	preview.id = "preview-actual";
+	preview.classList.add("m-5");
 
-		previewContainer.appendChild(preview);
-	};
+	// Remove the old preview if there is any
+	var maybe_old = document.getElementById("preview-actual");
+	if (maybe_old) {
+		previewContainer.removeChild(maybe_old);
+	}
+
+	previewContainer.appendChild(preview);
 }
 
 // Make preview for text content
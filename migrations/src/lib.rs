@@ -3,6 +3,20 @@ pub use sea_orm_migration::prelude::*;
 mod m20220101_000001_create_table;
 mod m20250507_105126_file_tracking;
 mod m20250528_144714_user_apitok;
+mod m20250601_093412_file_size;
+mod m20250603_181205_file_creation_time;
+mod m20250607_142530_file_download_limit;
+mod m20250610_201133_file_password;
+mod m20250614_110247_partial_upload;
+mod m20250617_164021_file_checksums;
+mod m20250621_090314_blob;
+mod m20250624_183950_blob_compression;
+mod m20250628_102544_blob_encryption;
+mod m20250702_193807_file_encrypted;
+mod m20250706_154420_file_slug;
+mod m20250709_172055_file_description;
+mod m20250713_101502_collection;
+mod m20250716_141208_file_content_type;
 
 pub struct Migrator;
 
@@ -13,6 +27,20 @@ impl MigratorTrait for Migrator {
             Box::new(m20220101_000001_create_table::Migration),
             Box::new(m20250507_105126_file_tracking::Migration),
             Box::new(m20250528_144714_user_apitok::Migration),
+            Box::new(m20250601_093412_file_size::Migration),
+            Box::new(m20250603_181205_file_creation_time::Migration),
+            Box::new(m20250607_142530_file_download_limit::Migration),
+            Box::new(m20250610_201133_file_password::Migration),
+            Box::new(m20250614_110247_partial_upload::Migration),
+            Box::new(m20250617_164021_file_checksums::Migration),
+            Box::new(m20250621_090314_blob::Migration),
+            Box::new(m20250624_183950_blob_compression::Migration),
+            Box::new(m20250628_102544_blob_encryption::Migration),
+            Box::new(m20250702_193807_file_encrypted::Migration),
+            Box::new(m20250706_154420_file_slug::Migration),
+            Box::new(m20250709_172055_file_description::Migration),
+            Box::new(m20250713_101502_collection::Migration),
+            Box::new(m20250716_141208_file_content_type::Migration),
         ]
     }
 }
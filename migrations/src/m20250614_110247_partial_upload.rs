@@ -0,0 +1,52 @@
+use sea_orm_migration::{prelude::*, schema};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(PartialUpload::Table)
+                    .if_not_exists()
+                    .col(schema::string(PartialUpload::Id).primary_key())
+                    .col(
+                        ColumnDef::new(PartialUpload::UserId)
+                            .integer()
+                            .null()
+                            .take(),
+                    )
+                    .col(schema::string(PartialUpload::Name))
+                    .col(schema::big_integer(PartialUpload::Length))
+                    .col(schema::string_null(PartialUpload::ExpiresIn))
+                    .col(schema::integer_null(PartialUpload::MaxDownloads))
+                    .col(schema::string_null(PartialUpload::PasswordHash))
+                    .col(schema::date_time(PartialUpload::CreationTime))
+                    .col(schema::date_time(PartialUpload::ExpirationTime))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PartialUpload::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+pub enum PartialUpload {
+    Table,
+    Id,
+    UserId,
+    Name,
+    Length,
+    ExpiresIn,
+    MaxDownloads,
+    PasswordHash,
+    CreationTime,
+    ExpirationTime,
+}
@@ -0,0 +1,76 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(File::Table)
+                    .add_column(ColumnDef::new(File::Slug).string().null())
+                    .to_owned(),
+            )
+            .await?;
+        // sqlite can't add unique columns, files without a slug don't collide
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-file-slug")
+                    .table(File::Table)
+                    .col(File::Slug)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PartialUpload::Table)
+                    .add_column(ColumnDef::new(PartialUpload::Slug).string().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PartialUpload::Table)
+                    .drop_column(PartialUpload::Slug)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx-file-slug")
+                    .table(File::Table)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(File::Table)
+                    .drop_column(File::Slug)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum File {
+    Table,
+    Slug,
+}
+
+#[derive(DeriveIden)]
+enum PartialUpload {
+    Table,
+    Slug,
+}
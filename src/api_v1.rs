@@ -1,18 +1,34 @@
 use std::ops::Deref;
-use std::str::FromStr;
+use std::time::SystemTime;
 
-use actix_multipart::form::MultipartForm;
+use actix_files::HttpRange;
+use actix_multipart::Multipart;
+use actix_session::Session;
+use actix_web::body::SizedStream;
+use actix_web::http::{StatusCode, header};
 use actix_web::web::Redirect;
-use actix_web::{HttpResponse, Responder, delete, get, post, web};
+use actix_web::{
+    HttpRequest, HttpResponse, Responder, ResponseError, delete, get, patch, post, put, web,
+};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures_util::StreamExt;
 use log::{debug, info, warn};
 use sea_orm::ModelTrait;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Serialize, Serializer};
 use serde_json::json;
 
+use crate::archive::{self, ArchiveFormat};
 use crate::auth::{AuthUser, MaybeAuthUser};
+use crate::compression::{self, StoredData};
+use crate::encryption;
 use crate::errors::Error;
-use crate::files::{FileID, FileUpload};
+use crate::file_edit::{self, FileEdit};
+use crate::file_list::FileListQuery;
+use crate::files::{FileID, UploadOptions};
+use crate::protection::{self, PasswordQuery};
 use crate::state::AppState;
+use crate::thumbnails::{self, ThumbnailSize};
 use crate::user::{ApiV1TokenRequest, User};
 
 #[derive(Debug, Serialize)]
@@ -23,8 +39,9 @@ pub struct ErrorResponse {
 
 #[post("/file")]
 pub async fn api_view_post_file(
+    req: HttpRequest,
     state: web::Data<AppState>,
-    MultipartForm(file_upload): MultipartForm<FileUpload>,
+    form: Multipart,
     identity: MaybeAuthUser,
 ) -> Result<impl Responder, Error> {
     let user = identity.user();
@@ -36,91 +53,463 @@ pub async fn api_view_post_file(
     }
 
     info!("Uploading File");
-    debug!("file upload data: {file_upload:?}");
+    let mut options = match UploadOptions::from_headers(&req) {
+        Ok(options) => options,
+        Err(error) => return Ok(json_error(error)),
+    };
+    // the form is a little larger than its files, but fail early if it can't fit at all
+    let expected_sha256 = options.sha256.as_ref().map(|s| s.to_ascii_lowercase());
+    if let Some(size) = content_length(&req)?
+        && let Err(error) = state
+            .check_storage_quota(user.as_ref(), size, expected_sha256.as_deref())
+            .await
+    {
+        return Ok(json_error(error));
+    }
+    let mut uploads = match state
+        .receive_upload_form(form, max_size, &mut options)
+        .await
+    {
+        Ok(stored) => stored,
+        Err(error) => return Ok(json_error(error)),
+    };
+    debug!("upload options: {options:?}");
+
+    // several files become a collection, which is what the response describes then
+    if uploads.len() > 1 {
+        return match state
+            .commit_collection(&uploads, user.as_ref(), &options)
+            .await
+        {
+            Ok(cinfos) => Ok(HttpResponse::Ok().json(cinfos)),
+            Err(error) => Ok(json_error(error)),
+        };
+    }
+    let (fid, name, received) = uploads.remove(0);
+    match state
+        .commit_upload(fid, &name, user.as_ref(), &options, &received, None)
+        .await
+    {
+        Ok(finfos) => Ok(HttpResponse::Ok().json(finfos)),
+        Err(error) => Ok(json_error(error)),
+    }
+}
+
+/// Upload the raw request body as a file called `name`, like `curl -T`
+///
+/// Responds with the [`FileInfos`](crate::files::FileInfos) if JSON is accepted, and with
+/// the download url as plain text otherwise.
+#[put("/file/{name}")]
+pub async fn api_view_put_file_name(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    payload: web::Payload,
+    identity: MaybeAuthUser,
+) -> Result<impl Responder, Error> {
+    let user = identity.user();
+    let max_size = state.max_upload_size(user.as_ref())?;
 
-    if file_upload.file.size as u64 > max_size {
-        warn!("Uploaded file is too large: {}", file_upload.file.size);
+    if user.is_none() && !state.config().accounts.allow_anon {
+        return Ok(HttpResponse::Unauthorized()
+            .json(json!({"error": format!("Only logged in users can upload to this instance")})));
+    }
+
+    let name = match file_edit::parse_name(&path.into_inner()) {
+        Ok(name) => name,
+        Err(error) => return Ok(json_error(error)),
+    };
+    info!("Uploading raw file: {name}");
+    let content_length = content_length(&req)?;
+    if content_length.is_some_and(|size| size > max_size) {
+        warn!("Uploaded file is too large: {content_length:?}");
         return Ok(HttpResponse::PayloadTooLarge()
             .json(json!({"error": format!("Uploaded file is too large: {max_size}")})));
     }
 
-    let fid = state.new_fid().await;
-    let name = file_upload
-        .file
-        .file_name
-        .expect("why does it not have a file name???") // TODO: this will crash when no filename exists
-        .to_string()
-        .trim()
-        .to_string();
-    let new_path = state.upload_datafile_for_fid(fid, &name, true)?;
+    let options = match UploadOptions::from_headers(&req) {
+        Ok(options) => options,
+        Err(error) => return Ok(json_error(error)),
+    };
+    // with the expected checksum, data that is already stored is not charged again
+    let expected_sha256 = options.sha256.as_ref().map(|s| s.to_ascii_lowercase());
+    if let Some(size) = content_length
+        && let Err(error) = state
+            .check_storage_quota(user.as_ref(), size, expected_sha256.as_deref())
+            .await
+    {
+        return Ok(json_error(error));
+    }
 
-    debug!(
-        "Path of temporary upload: {}",
-        file_upload.file.file.path().display()
-    );
-    info!("Uploading file to: {}", new_path.display());
+    let fid = state.new_fid().await?;
+    let limit = content_length.unwrap_or(max_size);
+    let finfos = match state.store_upload(fid, payload, limit).await {
+        Ok(received) => {
+            state
+                .commit_upload(fid, &name, user.as_ref(), &options, &received, None)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    let finfos = match finfos {
+        Ok(finfos) => finfos,
+        Err(error) => return Ok(json_error(error)),
+    };
 
-    std::fs::rename(file_upload.file.file.path(), &new_path).inspect_err(|e| {
-        warn!("Error while uploading file: {e}");
-    })?;
+    let wants_json = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(mime::APPLICATION_JSON.as_ref()));
+    if wants_json {
+        Ok(HttpResponse::Ok().json(finfos))
+    } else {
+        Ok(HttpResponse::Ok()
+            .content_type(mime::TEXT_PLAIN_UTF_8)
+            .body(format!("{}\n", finfos.url_raw)))
+    }
+}
 
+#[get("/file/{fid}")]
+pub async fn api_view_get_file_fid(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<PasswordQuery>,
+    session: Session,
+    identity: MaybeAuthUser,
+) -> Result<impl Responder, Error> {
+    let fid = state.resolve_fid(&path.into_inner()).await?;
+    let password = protection::request_password(&req, &query)?;
     state
-        .create_file_db_entry(fid, user.as_ref(), state.db())
+        .check_file_access(fid, password.as_deref(), &session, identity.user_ref())
         .await?;
+    let name = state.get_filename_for_fid(fid).await?;
+
+    let mut target = state.uri_api_file_fid_name(fid, &name).to_string();
+    if let Some(password) = &query.password {
+        target.push_str(&format!("?password={}", urlencoding::encode(password)));
+    }
+    Ok(Redirect::to(target))
+}
+
+#[delete("/file/{fid}")]
+pub async fn api_view_delete_file_fid(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    user: AuthUser,
+) -> Result<impl Responder, Error> {
+    let user = user.user();
+    let fid = state.resolve_fid(&path.into_inner()).await?;
+
+    state.delete_fid(&user, fid).await?;
+    Ok(HttpResponse::Ok().json(json!({"deleted": true})))
+}
+
+/// Body of [`api_view_put_file_fid_slug`]
+#[derive(Debug, Deserialize)]
+pub struct SlugRequest {
+    /// `None` removes the slug
+    slug: Option<String>,
+}
 
+/// Change the slug of a file of the logged in user, responds with the new
+/// [`FileInfos`](crate::files::FileInfos)
+#[put("/file/{fid}/slug")]
+pub async fn api_view_put_file_fid_slug(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    user: AuthUser,
+    web::Json(request): web::Json<SlugRequest>,
+) -> Result<impl Responder, Error> {
+    let user = user.user();
+    let fid = state.resolve_fid(&path.into_inner()).await?;
+
+    state.set_slug(&user, fid, request.slug.as_deref()).await?;
+    let name = state.get_filename_for_fid(fid).await?;
     Ok(HttpResponse::Ok().json(state.make_file_infos(fid, &name).await?))
 }
 
-#[get("/file/{fid}")]
-pub async fn api_view_get_file_fid(
+/// Change the expiration, name or description of a file of the logged in user, see
+/// [`FileEdit`]. Responds with the new [`FileInfos`](crate::files::FileInfos).
+#[patch("/file/{fid}")]
+pub async fn api_view_patch_file_fid(
     state: web::Data<AppState>,
     path: web::Path<String>,
+    user: AuthUser,
+    web::Json(edit): web::Json<FileEdit>,
 ) -> Result<impl Responder, Error> {
-    let fid: crate::files::FileID = FileID::from_str(&path.into_inner())?;
-    let name = state.get_filename_for_fid(fid)?;
+    let user = user.user();
+    let fid = state.resolve_fid(&path.into_inner()).await?;
+
+    let name = state.edit_file(&user, fid, &edit).await?;
+    Ok(HttpResponse::Ok().json(state.make_file_infos(fid, &name).await?))
+}
 
-    Ok(Redirect::to(
-        state.uri_api_file_fid_name(fid, &name).to_string(),
+/// The files that were uploaded together as the collection `id`, see
+/// [`CollectionInfos`](crate::collections::CollectionInfos)
+///
+/// Password protected files are only listed if the password is given like for a single file.
+#[get("/collection/{id}")]
+pub async fn api_view_get_collection_id(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<PasswordQuery>,
+    session: Session,
+    identity: MaybeAuthUser,
+) -> Result<impl Responder, Error> {
+    let collection = state.get_collection(&path.into_inner()).await?;
+    let password = protection::request_password(&req, &query)?;
+    Ok(HttpResponse::Ok().json(
+        state
+            .make_collection_infos(
+                collection,
+                password.as_deref(),
+                &session,
+                identity.user_ref(),
+            )
+            .await?,
     ))
 }
 
-#[delete("/file/{fid}")]
-pub async fn api_view_delete_file_fid(
+/// Query of [`api_view_get_collection_id_archive`]
+#[derive(Debug, Deserialize)]
+pub struct ArchiveQuery {
+    #[serde(default)]
+    format: ArchiveFormat,
+    /// Comma separated ids of the files that go into the archive, all files of the collection
+    /// if not given
+    files: Option<String>,
+}
+
+/// Download the files of a collection as one archive, see [`ArchiveQuery`] for the options
+///
+/// A password that protects the files is given like for a single file. Each file in the archive
+/// counts as a download of it. Files with a download limit that were left out are listed in the
+/// [`ARCHIVE_OMITTED_HEADER`](archive::ARCHIVE_OMITTED_HEADER).
+#[get("/collection/{id}/archive")]
+pub async fn api_view_get_collection_id_archive(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<ArchiveQuery>,
+    password_query: web::Query<PasswordQuery>,
+    session: Session,
+    identity: MaybeAuthUser,
+) -> Result<impl Responder, Error> {
+    let collection = state.get_collection(&path.into_inner()).await?;
+    let password = protection::request_password(&req, &password_query)?;
+    let selection = query
+        .files
+        .as_deref()
+        .map(|files| {
+            files
+                .split(',')
+                .map(|fid| fid.trim().parse())
+                .collect::<Result<Vec<FileID>, Error>>()
+        })
+        .transpose()?;
+    let (members, omitted) = state
+        .archive_members(
+            &collection,
+            selection.as_deref(),
+            password.as_deref(),
+            &session,
+            identity.user_ref(),
+        )
+        .await?;
+    info!(
+        "Downloading {} files of collection {} as {}",
+        members.len(),
+        collection.id,
+        query.format.extension()
+    );
+
+    let stream = archive::stream_archive(state.clone(), members, query.format);
+    let mut res = HttpResponse::Ok();
+    if !omitted.is_empty() {
+        let omitted: Vec<String> = omitted.iter().map(FileID::to_string).collect();
+        res.insert_header((archive::ARCHIVE_OMITTED_HEADER, omitted.join(",")));
+    }
+    Ok(res
+        .content_type(query.format.content_type())
+        .insert_header(header::ContentDisposition {
+            disposition: header::DispositionType::Attachment,
+            parameters: vec![header::DispositionParam::Filename(format!(
+                "{}.{}",
+                collection.id,
+                query.format.extension()
+            ))],
+        })
+        .streaming(stream))
+}
+
+/// Delete a collection of the logged in user together with all of its files
+#[delete("/collection/{id}")]
+pub async fn api_view_delete_collection_id(
     state: web::Data<AppState>,
     path: web::Path<String>,
     user: AuthUser,
 ) -> Result<impl Responder, Error> {
     let user = user.user();
-    let fid: crate::files::FileID = FileID::from_str(&path.into_inner())?;
 
-    state.delete_fid(&user, fid).await?;
+    state.delete_collection(&user, &path.into_inner()).await?;
     Ok(HttpResponse::Ok().json(json!({"deleted": true})))
 }
 
+/// The files of the logged in user, see [`FileListQuery`] for the options
+#[get("/files")]
+pub async fn api_view_get_files(
+    state: web::Data<AppState>,
+    query: web::Query<FileListQuery>,
+    user: AuthUser,
+) -> Result<impl Responder, Error> {
+    Ok(HttpResponse::Ok().json(state.list_files(user.user_ref(), &query).await?))
+}
+
 #[get("/file/{fid}/{filename}")]
 pub async fn api_view_get_file_fid_name(
-    req: actix_web::HttpRequest,
+    req: HttpRequest,
     state: web::Data<AppState>,
     urlpath: web::Path<(String, String)>,
+    query: web::Query<PasswordQuery>,
+    session: Session,
+    identity: MaybeAuthUser,
 ) -> Result<impl Responder, Error> {
     info!("Downloading file for fid");
     let urlargs = urlpath.into_inner();
-    let fid = FileID::from_str(&urlargs.0)?;
+    let fid = state.resolve_fid(&urlargs.0).await?;
+    let password = protection::request_password(&req, &query)?;
+    state
+        .check_file_access(fid, password.as_deref(), &session, identity.user_ref())
+        .await?;
+    let name = urlencoding::decode(urlargs.1.as_str())?;
+    // make sure the file exists before counting a download of it
+    let data = state.stored_data(fid, &name).await?;
+    debug!("Get file: {data:?}");
+
+    let downloads_remaining = state.register_download(fid).await?;
+    serve_object(&req, &state, fid, &data, &name, downloads_remaining).await
+}
+
+/// Download a single entry of an archive, see [`archive_contents`](crate::archive_contents)
+///
+/// This counts as a download of the archive.
+#[get("/file/{fid}/{filename}/entry/{path:.*}")]
+pub async fn api_view_get_file_fid_name_entry(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    urlpath: web::Path<(String, String, String)>,
+    query: web::Query<PasswordQuery>,
+    session: Session,
+    identity: MaybeAuthUser,
+) -> Result<impl Responder, Error> {
+    let urlargs = urlpath.into_inner();
+    let fid = state.resolve_fid(&urlargs.0).await?;
+    let password = protection::request_password(&req, &query)?;
+    state
+        .check_file_access(fid, password.as_deref(), &session, identity.user_ref())
+        .await?;
+    let name = urlencoding::decode(urlargs.1.as_str())?;
+    let path = urlencoding::decode(urlargs.2.as_str())?;
+    info!("Downloading entry {path} of archive {fid}");
+    if state
+        .get_file_db_entry(fid, state.db())
+        .await?
+        .is_some_and(|file| file.encrypted)
+    {
+        return Err(Error::NotAnArchive);
+    }
+    // make sure the entry exists before counting a download of the archive
+    let (size, mut stream) = state.stream_archive_entry(fid, &name, &path).await?;
+
+    if state.register_download(fid).await? == Some(0) {
+        info!("Last allowed download of {fid}, removing it afterwards");
+        let guard = RemoveOnDrop {
+            state: state.clone(),
+            fid,
+        };
+        stream = stream
+            .inspect(move |_| {
+                let _ = &guard;
+            })
+            .boxed();
+    }
+    let entry_name = path.rsplit('/').next().unwrap_or_default().to_string();
+    let content_type = match std::path::Path::new(&entry_name).extension() {
+        Some(ext) => actix_files::file_extension_to_mime(&ext.to_string_lossy()),
+        None => mime::APPLICATION_OCTET_STREAM,
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(header::ContentDisposition {
+            disposition: header::DispositionType::Attachment,
+            parameters: vec![header::DispositionParam::Filename(entry_name)],
+        })
+        .body(SizedStream::new(size, stream)))
+}
+
+/// Query of [`api_view_get_file_fid_name_thumbnail`]
+#[derive(Debug, Deserialize)]
+pub struct ThumbnailQuery {
+    #[serde(default)]
+    size: ThumbnailSize,
+}
+
+/// A smaller version of an image, see [`thumbnails`](crate::thumbnails)
+///
+/// This does not count as a download. If the thumbnail was not made yet, this fails with
+/// [`Error::ThumbnailNotFound`] and queues it to be made.
+#[get("/file/{fid}/{filename}/thumbnail")]
+pub async fn api_view_get_file_fid_name_thumbnail(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    urlpath: web::Path<(String, String)>,
+    query: web::Query<ThumbnailQuery>,
+    password_query: web::Query<PasswordQuery>,
+    session: Session,
+    identity: MaybeAuthUser,
+) -> Result<impl Responder, Error> {
+    let urlargs = urlpath.into_inner();
+    let fid = state.resolve_fid(&urlargs.0).await?;
+    let password = protection::request_password(&req, &password_query)?;
+    state
+        .check_file_access(fid, password.as_deref(), &session, identity.user_ref())
+        .await?;
     let name = urlencoding::decode(urlargs.1.as_str())?;
-    let path = state.upload_datafile_for_fid(fid, &name, false)?;
-    debug!("Get file: {}", path.display());
-    let file = actix_files::NamedFile::open_async(&path).await?;
-    Ok(file.into_response(&req))
+    let Some(thumbnail) = state.thumbnail(fid, &name, query.size).await? else {
+        // files that never get thumbnails are left out of the queue
+        if state
+            .get_file_db_entry(fid, state.db())
+            .await?
+            .is_some_and(|file| !file.encrypted && file.max_downloads.is_none())
+        {
+            state.queue_thumbnails(fid);
+        }
+        return Err(Error::ThumbnailNotFound);
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(thumbnails::thumbnail_content_type(&thumbnail))
+        .body(thumbnail))
 }
 
 #[get("/file/{fid}/{filename}/info")]
 pub async fn api_view_get_file_fid_name_info(
+    req: HttpRequest,
     state: web::Data<AppState>,
     urlpath: web::Path<(String, String)>,
+    query: web::Query<PasswordQuery>,
+    session: Session,
+    identity: MaybeAuthUser,
 ) -> Result<impl Responder, Error> {
     info!("Get information on file for fid");
     let urlargs = urlpath.into_inner();
-    let fid = FileID::from_str(&urlargs.0)?;
+    let fid = state.resolve_fid(&urlargs.0).await?;
+    let password = protection::request_password(&req, &query)?;
+    state
+        .check_file_access(fid, password.as_deref(), &session, identity.user_ref())
+        .await?;
     let name = urlargs.1;
     let finfos = state.make_file_infos(fid, &name).await?;
     debug!("finfos: {finfos:#?}");
@@ -184,6 +573,176 @@ pub async fn api_view_delete_auth_token_name(
     })))
 }
 
+/// Respond with the stored `data` of `fid`, honoring `Range` requests
+///
+/// If the storage backend supports it, the client is redirected to download the object directly.
+/// Files with a download limit are always streamed, so that the limit can't be bypassed, and
+/// removed once the last allowed download is done. Compressed data is decompressed on the fly,
+/// unless the client accepts its compression as the `Content-Encoding`. Encrypted data is
+/// always decrypted by the server, so it is never downloaded from the storage directly.
+async fn serve_object(
+    req: &HttpRequest,
+    state: &web::Data<AppState>,
+    fid: FileID,
+    data: &StoredData,
+    name: &str,
+    downloads_remaining: Option<u32>,
+) -> Result<HttpResponse, Error> {
+    let key = data.key.as_str();
+    let file = state.get_file_db_entry(fid, state.db()).await?;
+    // the server can't tell what the data of an end-to-end encrypted file is
+    let content_type = match std::path::Path::new(name).extension() {
+        Some(ext) if !file.as_ref().is_some_and(|file| file.encrypted) => {
+            actix_files::file_extension_to_mime(&ext.to_string_lossy())
+        }
+        _ => mime::APPLICATION_OCTET_STREAM,
+    };
+    if downloads_remaining.is_none()
+        && data.compression.is_none()
+        && data.data_key.is_none()
+        && let Some(url) = state
+            .storage()
+            .presigned_url(key, name, content_type.as_ref())
+            .await?
+    {
+        debug!("Redirecting download to presigned url");
+        return Ok(HttpResponse::TemporaryRedirect()
+            .insert_header((header::LOCATION, url))
+            .finish());
+    }
+
+    let meta = state.storage().stat(key).await?;
+
+    let mut res = HttpResponse::Ok();
+    res.content_type(content_type)
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(header::ContentDisposition {
+            disposition: header::DispositionType::Inline,
+            parameters: vec![header::DispositionParam::Filename(name.to_string())],
+        })
+        .insert_header(header::LastModified(
+            SystemTime::from(meta.time_modified.and_utc()).into(),
+        ));
+    // compressed data is passed through as it is stored if the client can decompress it
+    let pass_through = data.compression.filter(|compression| {
+        compression::accepts_encoding(req, *compression)
+            && !req.headers().contains_key(header::RANGE)
+    });
+    if data.compression.is_some() {
+        res.insert_header((header::VARY, header::ACCEPT_ENCODING.as_str()));
+    }
+    if let Some(sha256) = file.and_then(|file| file.sha256) {
+        let etag = match pass_through {
+            Some(compression) => format!("{sha256}-{}", compression.name()),
+            None => sha256.clone(),
+        };
+        res.insert_header(header::ETag(header::EntityTag::new_strong(etag)));
+        // the digest of the whole file, even for range requests
+        if let Ok(digest) = hex::decode(&sha256) {
+            res.insert_header(("Digest", format!("sha-256={}", BASE64.encode(digest))));
+        }
+    }
+
+    let size = data.size;
+    let mut range = None;
+    if let Some(range_header) = req.headers().get(header::RANGE) {
+        let range_header = range_header
+            .to_str()
+            .map_err(|e| Error::BadHeader(e.to_string()))?;
+        match HttpRange::parse(range_header, size) {
+            // multiple ranges in one response are not supported, just serve the whole thing
+            Ok(ranges) if ranges.len() == 1 => {
+                let r = ranges[0];
+                res.status(StatusCode::PARTIAL_CONTENT)
+                    .insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                        range: Some((r.start, r.start + r.length - 1)),
+                        instance_length: Some(size),
+                    }));
+                range = Some(r.start..r.start + r.length);
+            }
+            Ok(_) => (),
+            Err(_) => {
+                return Ok(HttpResponse::RangeNotSatisfiable()
+                    .insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                        range: None,
+                        instance_length: Some(size),
+                    }))
+                    .finish());
+            }
+        }
+    }
+
+    let (length, mut stream) = match pass_through {
+        Some(compression) => {
+            debug!("Passing {} compressed data through", compression.name());
+            res.insert_header((header::CONTENT_ENCODING, compression.name()));
+            let length = match data.data_key {
+                Some(_) => encryption::decrypted_size(meta.size),
+                None => meta.size,
+            };
+            (length, state.stream_stored(data, None).await?)
+        }
+        None => (
+            range.as_ref().map_or(size, |r| r.end - r.start),
+            state.stream_data(data, range).await?,
+        ),
+    };
+    if downloads_remaining == Some(0) {
+        info!("Last allowed download of {fid}, removing it afterwards");
+        let guard = RemoveOnDrop {
+            state: state.clone(),
+            fid,
+        };
+        stream = stream
+            .inspect(move |_| {
+                let _ = &guard;
+            })
+            .boxed();
+    }
+    Ok(res.body(SizedStream::new(length, stream)))
+}
+
+/// Removes a file when dropped, used to remove files after their last allowed download
+struct RemoveOnDrop {
+    state: web::Data<AppState>,
+    fid: FileID,
+}
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let state = self.state.clone();
+        let fid = self.fid;
+        actix_web::rt::spawn(async move {
+            let removed = match state.get_file_db_entry(fid, state.db()).await {
+                Ok(Some(file)) => state.remove_file(file).await,
+                Ok(None) => Ok(0),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = removed {
+                warn!("Could not remove file {fid} after its last download: {e}");
+            }
+        });
+    }
+}
+
+/// The `Content-Length` of `req`, if it has one
+fn content_length(req: &HttpRequest) -> Result<Option<u64>, Error> {
+    match req.headers().get(header::CONTENT_LENGTH) {
+        Some(value) => Ok(Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| Error::BadHeader(header::CONTENT_LENGTH.to_string()))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+fn json_error(error: Error) -> HttpResponse {
+    HttpResponse::build(error.status_code()).json(ErrorResponse { error })
+}
+
 fn ser_error<S>(err: &crate::Error, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
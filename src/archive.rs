@@ -0,0 +1,295 @@
+//! Downloading the files of a collection as one archive
+//!
+//! The archive is built while it is downloaded: a task writes it into a pipe that the response
+//! reads from, so it is never stored anywhere. The members are read with
+//! [`AppState::stream_data`], which means that they are in the archive as they were uploaded,
+//! even if they are compressed or encrypted at rest.
+
+use std::collections::HashSet;
+
+use actix_session::Session;
+use actix_web::web;
+use async_compression::tokio::write::GzipEncoder;
+use async_zip::base::write::ZipFileWriter;
+use async_zip::{Compression, ZipDateTime, ZipEntryBuilder};
+use chrono::NaiveDateTime;
+use futures_util::{AsyncWriteExt as _, StreamExt, TryStreamExt};
+use log::{debug, warn};
+use serde::Deserialize;
+use tokio::io::{AsyncWriteExt as _, DuplexStream};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::compression::StoredData;
+use crate::db::schema::collection::Model as CollectionM;
+use crate::errors::Error;
+use crate::files::{DEFAULT_UPLOAD_NAME, FileID};
+use crate::state::AppState;
+use crate::storage::ByteStream;
+use crate::user::User;
+
+/// Lists the ids of the files that were left out of an archive because of their download limit
+pub const ARCHIVE_OMITTED_HEADER: &str = "Archive-Omitted-Files";
+/// How much of the archive may be written before the client has read it
+const ARCHIVE_BUFFER_SIZE: usize = 64 * 1024;
+/// Unix permissions of the members
+const MEMBER_MODE: u16 = 0o644;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "zip")]
+    Zip,
+    #[serde(rename = "tar.gz")]
+    TarGz,
+}
+
+/// A file that goes into an archive
+#[derive(Debug)]
+pub struct ArchiveMember {
+    pub fid: FileID,
+    /// Unique within the archive
+    pub name: String,
+    pub data: StoredData,
+    pub time_created: NaiveDateTime,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Zip => "application/zip",
+            Self::TarGz => "application/gzip",
+        }
+    }
+}
+
+impl AppState {
+    /// The files of `collection` that go into an archive downloaded by whoever makes this
+    /// request, only those in `selection` if it is given
+    ///
+    /// Every file must be accessible with `password`, see
+    /// [`check_file_access`](Self::check_file_access). Files with a download limit can't be
+    /// in an archive, as the limit can't be kept if the archive is not downloaded completely.
+    /// Selecting one fails with [`Error::LimitedFileInArchive`], otherwise they are left out
+    /// and returned next to the members. The downloads of the members are counted once their
+    /// entry is written.
+    pub async fn archive_members(
+        &self,
+        collection: &CollectionM,
+        selection: Option<&[FileID]>,
+        password: Option<&str>,
+        session: &Session,
+        user: Option<&User>,
+    ) -> Result<(Vec<ArchiveMember>, Vec<FileID>), Error> {
+        let mut files = self.collection_files(&collection.id).await?;
+        // the collection itself is only removed by the garbage collector
+        if files.is_empty() {
+            return Err(Error::CollectionNotFound);
+        }
+        if let Some(selection) = selection {
+            if let Some(fid) = selection
+                .iter()
+                .find(|fid| !files.iter().any(|file| file.id == fid.inner()))
+            {
+                debug!("File {fid} is not in collection {}", collection.id);
+                return Err(Error::FileNotFound);
+            }
+            files.retain(|file| selection.iter().any(|fid| fid.inner() == file.id));
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut found = Vec::new();
+        let mut omitted = Vec::new();
+        for file in files {
+            let fid: FileID = file.id.into();
+            if file.max_downloads.is_some() {
+                if selection.is_some() {
+                    return Err(Error::LimitedFileInArchive(fid));
+                }
+                debug!("Leaving file {fid} with a download limit out of the archive");
+                omitted.push(fid);
+                continue;
+            }
+            self.check_file_access(fid, password, session, user).await?;
+            let name = match file.name {
+                Some(name) => name,
+                None => self.get_filename_for_fid(fid).await?,
+            };
+            let data = self.stored_data(fid, &name).await?;
+            found.push((fid, name, data, file.creation_time));
+        }
+
+        if found.is_empty() {
+            return Err(match omitted.first() {
+                Some(fid) => Error::LimitedFileInArchive(*fid),
+                None => Error::FileNotFound,
+            });
+        }
+
+        let names = archive_names(found.iter().map(|(_, name, _, _)| name.as_str()));
+        let members = found
+            .into_iter()
+            .zip(names)
+            .map(|((fid, _, data, time_created), name)| ArchiveMember {
+                fid,
+                name,
+                data,
+                time_created: time_created.unwrap_or_else(|| chrono::Utc::now().naive_utc()),
+            })
+            .collect();
+        Ok((members, omitted))
+    }
+}
+
+/// Stream an archive of `members` in `format`
+///
+/// If building the archive fails, the stream ends with the error instead of the rest of the
+/// archive.
+pub fn stream_archive(
+    state: web::Data<AppState>,
+    members: Vec<ArchiveMember>,
+    format: ArchiveFormat,
+) -> ByteStream {
+    let (writer, reader) = tokio::io::duplex(ARCHIVE_BUFFER_SIZE);
+    let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+    actix_web::rt::spawn(async move {
+        let written = write_archive(&state, members, format, writer).await;
+        if let Err(e) = &written {
+            warn!("Could not write archive: {e}");
+        }
+        let _ = done_tx.send(written);
+    });
+
+    let result = futures_util::stream::once(async move {
+        match done_rx.await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(Err(e)),
+            Err(_) => Some(Err(
+                std::io::Error::from(std::io::ErrorKind::BrokenPipe).into()
+            )),
+        }
+    })
+    .filter_map(futures_util::future::ready);
+    ReaderStream::new(reader)
+        .map_err(Error::from)
+        .chain(result)
+        .boxed()
+}
+
+async fn write_archive(
+    state: &AppState,
+    members: Vec<ArchiveMember>,
+    format: ArchiveFormat,
+    out: DuplexStream,
+) -> Result<(), Error> {
+    match format {
+        ArchiveFormat::Zip => {
+            let mut zip = ZipFileWriter::with_tokio(out);
+            for member in members {
+                let entry = ZipEntryBuilder::new(member.name.into(), Compression::Deflate)
+                    .last_modification_date(ZipDateTime::from_chrono(
+                        &member.time_created.and_utc(),
+                    ))
+                    .unix_permissions(MEMBER_MODE);
+                let mut entry = zip.write_entry_stream(entry).await?;
+                let mut data = state.stream_data(&member.data, None).await?;
+                while let Some(chunk) = data.try_next().await? {
+                    entry.write_all(&chunk).await?;
+                }
+                entry.close().await?;
+                count_download(state, member.fid).await;
+            }
+            zip.close().await?;
+        }
+        ArchiveFormat::TarGz => {
+            let mut tar = tokio_tar::Builder::new_non_terminated(GzipEncoder::new(out));
+            for member in members {
+                let mut header = tokio_tar::Header::new_gnu();
+                header.set_entry_type(tokio_tar::EntryType::Regular);
+                header.set_size(member.data.size);
+                header.set_mode(MEMBER_MODE.into());
+                header.set_mtime(member.time_created.and_utc().timestamp().max(0) as u64);
+                let data = state.stream_data(&member.data, None).await?;
+                let data = StreamReader::new(data.map_err(std::io::Error::other));
+                tar.append_data(&mut header, &member.name, data).await?;
+                count_download(state, member.fid).await;
+            }
+            tar.finish().await?;
+            // writes the end of the gzip stream
+            tar.into_inner().await?.shutdown().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Count the download of a member whose entry was written
+async fn count_download(state: &AppState, fid: FileID) {
+    // the data was already sent, so this does not fail the archive
+    if let Err(e) = state.register_download(fid).await {
+        warn!("Could not count the download of {fid} in an archive: {e}");
+    }
+}
+
+/// Names for files in an archive, in the same order
+///
+/// Anything that could make a member end up outside of where the archive is extracted is
+/// replaced, and names that appear more than once are numbered like `report (2).pdf`.
+pub fn archive_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut taken = HashSet::new();
+    let mut unique = Vec::new();
+    for name in names {
+        let mut name: String = name
+            .chars()
+            .map(|c| match c {
+                '/' | '\\' => '_',
+                c if c.is_control() => '_',
+                c => c,
+            })
+            .collect();
+        if name.is_empty() || name == "." || name == ".." {
+            name = DEFAULT_UPLOAD_NAME.to_string();
+        }
+        let (stem, extension) = match name.rfind('.') {
+            Some(dot) if dot > 0 => name.split_at(dot),
+            _ => (name.as_str(), ""),
+        };
+        let mut candidate = name.clone();
+        let mut n = 2;
+        while taken.contains(&candidate) {
+            candidate = format!("{stem} ({n}){extension}");
+            n += 1;
+        }
+        taken.insert(candidate.clone());
+        unique.push(candidate);
+    }
+    unique
+}
+
+#[cfg(test)]
+mod test {
+    use super::archive_names;
+
+    #[test]
+    fn test_archive_names() {
+        assert_eq!(
+            archive_names(["a.txt", "b.txt", "a.txt", "a.txt", "a (2).txt"]),
+            ["a.txt", "b.txt", "a (2).txt", "a (3).txt", "a (2) (2).txt"]
+        );
+        assert_eq!(
+            archive_names(["../etc/passwd", "..", "", ".bashrc", ".bashrc"]),
+            [
+                ".._etc_passwd",
+                "upload",
+                "upload (2)",
+                ".bashrc",
+                ".bashrc (2)"
+            ]
+        );
+    }
+}
@@ -0,0 +1,647 @@
+//! Looking into uploaded archives
+//!
+//! The entries of ZIP files and tarballs are listed on their preview page, and each of them can
+//! be downloaded on its own. ZIP files are read from their central directory, so only the parts
+//! that are needed are fetched from the storage. Tarballs have no index and are read from the
+//! start.
+//!
+//! Archives can decompress to much more data than they take up, so the entries of an archive may
+//! only be [`MAX_COMPRESSION_RATIO`] times as large as their compressed data, and every entry is
+//! cut off where it gets larger than it claims to be. As every look into a tarball reads it from
+//! the start, at most [`MAX_TARBALL_READ`] bytes of it are read, however large it is.
+
+use std::io;
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_compression::tokio::bufread::{DeflateDecoder, GzipDecoder};
+use chrono::{NaiveDate, NaiveDateTime};
+use futures_util::{StreamExt, TryStreamExt};
+use log::debug;
+use serde::Serialize;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::compression::StoredData;
+use crate::errors::Error;
+use crate::files::FileID;
+use crate::state::AppState;
+use crate::storage::ByteStream;
+
+/// Most entries that an archive can have to be browsed
+pub const MAX_ARCHIVE_ENTRIES: usize = 10_000;
+/// How many times larger than its compressed data an entry or a tarball may be
+pub const MAX_COMPRESSION_RATIO: u64 = 100;
+/// Data this small may always be decompressed, no matter how well it was compressed
+const MIN_DECOMPRESSION_LIMIT: u64 = 1024 * 1024;
+/// How far a tarball is read after decompressing, later entries can't be browsed
+pub const MAX_TARBALL_READ: u64 = 256 * 1024 * 1024;
+/// Largest central directory of a ZIP file that is read
+const MAX_CENTRAL_DIRECTORY_SIZE: u64 = 16 * 1024 * 1024;
+
+const ZIP_EOCD_SIGNATURE: u32 = 0x06054b50;
+const ZIP_EOCD_SIZE: usize = 22;
+const ZIP64_EOCD_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const ZIP64_EOCD_LOCATOR_SIZE: usize = 20;
+const ZIP64_EOCD_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_EOCD_SIZE: usize = 56;
+const ZIP_CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP_CENTRAL_HEADER_SIZE: usize = 46;
+const ZIP_LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const ZIP_LOCAL_HEADER_SIZE: usize = 30;
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+
+/// Kinds of archives that can be browsed, told apart by the name of the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+/// An entry of an archive, as listed on the preview page
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveEntry {
+    pub path: String,
+    pub size: u64,
+    /// human readable size
+    pub size_human: String,
+    pub time_modified: Option<NaiveDateTime>,
+    pub is_dir: bool,
+    /// Download of the entry, `None` for directories and other entries that are not files
+    pub url: Option<String>,
+}
+
+/// An entry from the central directory of a ZIP file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ZipEntry {
+    path: String,
+    method: u16,
+    encrypted: bool,
+    crc32: u32,
+    compressed_size: u64,
+    size: u64,
+    time_modified: Option<NaiveDateTime>,
+    local_header_offset: u64,
+}
+
+/// Where the central directory of a ZIP file is
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CentralDirectory {
+    At {
+        range: Range<u64>,
+        entries: u64,
+    },
+    /// The real location is in the ZIP64 end of central directory record at this offset
+    Zip64(u64),
+}
+
+impl ArchiveKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+impl ZipEntry {
+    fn is_dir(&self) -> bool {
+        self.path.ends_with('/')
+    }
+}
+
+impl AppState {
+    /// The entries of the archive `name` with the id `fid`
+    pub async fn archive_entries(
+        &self,
+        fid: FileID,
+        name: &str,
+    ) -> Result<Vec<ArchiveEntry>, Error> {
+        let kind = ArchiveKind::from_name(name).ok_or(Error::NotAnArchive)?;
+        let data = self.stored_data(fid, name).await?;
+        let entries: Vec<(String, u64, Option<NaiveDateTime>, bool, bool)> = match kind {
+            ArchiveKind::Zip => self
+                .zip_entries(&data)
+                .await?
+                .into_iter()
+                .map(|entry| {
+                    let is_dir = entry.is_dir();
+                    (entry.path, entry.size, entry.time_modified, is_dir, !is_dir)
+                })
+                .collect(),
+            ArchiveKind::Tar | ArchiveKind::TarGz => {
+                let mut entries = Vec::new();
+                let mut archive = tokio_tar::Archive::new(self.tar_reader(&data, kind).await?);
+                let mut found = archive.entries()?;
+                while let Some(entry) = found.try_next().await.map_err(archive_error)? {
+                    if entries.len() >= MAX_ARCHIVE_ENTRIES {
+                        return Err(too_many_entries());
+                    }
+                    let header = entry.header();
+                    let time_modified = header
+                        .mtime()
+                        .ok()
+                        .and_then(|mtime| chrono::DateTime::from_timestamp(mtime as i64, 0))
+                        .map(|time| time.naive_utc());
+                    entries.push((
+                        entry.path()?.to_string_lossy().into_owned(),
+                        header.size()?,
+                        time_modified,
+                        header.entry_type().is_dir(),
+                        header.entry_type().is_file(),
+                    ));
+                }
+                entries
+            }
+        };
+
+        Ok(entries
+            .into_iter()
+            .map(
+                |(path, size, time_modified, is_dir, is_file)| ArchiveEntry {
+                    url: is_file.then(|| {
+                        self.uri_api_file_fid_name_entry(fid, name, &path)
+                            .to_string()
+                    }),
+                    size_human: human_bytes::human_bytes(size as f64),
+                    path,
+                    size,
+                    time_modified,
+                    is_dir,
+                },
+            )
+            .collect())
+    }
+
+    /// Stream the entry at `path` of the archive `name` with the id `fid`
+    ///
+    /// Returns the size of the entry and its data.
+    pub async fn stream_archive_entry(
+        &self,
+        fid: FileID,
+        name: &str,
+        path: &str,
+    ) -> Result<(u64, ByteStream), Error> {
+        let kind = ArchiveKind::from_name(name).ok_or(Error::NotAnArchive)?;
+        let data = self.stored_data(fid, name).await?;
+        match kind {
+            ArchiveKind::Zip => {
+                let entry = self
+                    .zip_entries(&data)
+                    .await?
+                    .into_iter()
+                    .find(|entry| entry.path == path && !entry.is_dir())
+                    .ok_or(Error::ArchiveEntryNotFound)?;
+                let stream = self.stream_zip_entry(&data, &entry).await?;
+                Ok((entry.size, stream))
+            }
+            ArchiveKind::Tar | ArchiveKind::TarGz => {
+                let mut archive = tokio_tar::Archive::new(self.tar_reader(&data, kind).await?);
+                let mut entries = archive.entries()?;
+                let mut seen = 0;
+                while let Some(entry) = entries.try_next().await.map_err(archive_error)? {
+                    seen += 1;
+                    if seen > MAX_ARCHIVE_ENTRIES {
+                        return Err(too_many_entries());
+                    }
+                    if entry.header().entry_type().is_file()
+                        && entry.path()?.to_string_lossy() == path
+                    {
+                        let size = entry.header().size()?;
+                        if size > decompression_limit(data.size) {
+                            return Err(Error::ArchiveLimitExceeded(format!(
+                                "{path} is compressed too well"
+                            )));
+                        }
+                        let stream = ReaderStream::new(LimitedReader::new(entry, size, None))
+                            .map_err(Error::from)
+                            .boxed();
+                        return Ok((size, stream));
+                    }
+                }
+                Err(Error::ArchiveEntryNotFound)
+            }
+        }
+    }
+
+    /// The content of a tarball, decompressed if needed and limited to
+    /// [`MAX_COMPRESSION_RATIO`] times its size and [`MAX_TARBALL_READ`]
+    async fn tar_reader(
+        &self,
+        data: &StoredData,
+        kind: ArchiveKind,
+    ) -> Result<LimitedReader<Pin<Box<dyn AsyncRead + Send>>>, Error> {
+        let stored = StreamReader::new(
+            self.stream_data(data, None)
+                .await?
+                .map_err(io::Error::other),
+        );
+        let reader: Pin<Box<dyn AsyncRead + Send>> = match kind {
+            ArchiveKind::TarGz => Box::pin(GzipDecoder::new(stored)),
+            _ => Box::pin(stored),
+        };
+        Ok(LimitedReader::new(
+            reader,
+            decompression_limit(data.size).min(MAX_TARBALL_READ),
+            None,
+        ))
+    }
+
+    /// The entries in the central directory of a ZIP file
+    async fn zip_entries(&self, data: &StoredData) -> Result<Vec<ZipEntry>, Error> {
+        let tail_length = (ZIP_EOCD_SIZE + u16::MAX as usize + ZIP64_EOCD_LOCATOR_SIZE) as u64;
+        let tail_start = data.size.saturating_sub(tail_length);
+        let tail = self.read_data(data, Some(tail_start..data.size)).await?;
+        let (range, count) = match find_central_directory(&tail, tail_start)? {
+            CentralDirectory::At { range, entries } => (range, entries),
+            CentralDirectory::Zip64(offset) => {
+                let record = self
+                    .read_data(
+                        data,
+                        Some(zip_range(offset, ZIP64_EOCD_SIZE as u64, data.size)?),
+                    )
+                    .await?;
+                parse_zip64_eocd(&record)?
+            }
+        };
+        if count > MAX_ARCHIVE_ENTRIES as u64 {
+            return Err(too_many_entries());
+        }
+        if range.end - range.start > MAX_CENTRAL_DIRECTORY_SIZE || range.end > data.size {
+            return Err(Error::BadArchive(
+                "the central directory is too large".to_string(),
+            ));
+        }
+        let directory = self.read_data(data, Some(range)).await?;
+        parse_central_directory(&directory, count, data.size)
+    }
+
+    async fn stream_zip_entry(
+        &self,
+        data: &StoredData,
+        entry: &ZipEntry,
+    ) -> Result<ByteStream, Error> {
+        if entry.encrypted {
+            return Err(Error::BadArchive(format!("{} is encrypted", entry.path)));
+        }
+        let offset = entry.local_header_offset;
+        let header = self
+            .read_data(
+                data,
+                Some(zip_range(offset, ZIP_LOCAL_HEADER_SIZE as u64, data.size)?),
+            )
+            .await?;
+        if header.len() < ZIP_LOCAL_HEADER_SIZE
+            || read_u32(&header, 0) != ZIP_LOCAL_HEADER_SIGNATURE
+        {
+            return Err(Error::BadArchive(format!(
+                "no local header for {}",
+                entry.path
+            )));
+        }
+        let start = zip_range(
+            offset,
+            ZIP_LOCAL_HEADER_SIZE as u64
+                + read_u16(&header, 26) as u64
+                + read_u16(&header, 28) as u64,
+            data.size,
+        )?
+        .end;
+        // only the bytes that are actually there, not the size the entry claims
+        let range = zip_range(start, entry.compressed_size, data.size)?;
+        if entry.size > decompression_limit(range.end - range.start) {
+            return Err(Error::ArchiveLimitExceeded(format!(
+                "{} is compressed too well",
+                entry.path
+            )));
+        }
+        debug!(
+            "Reading ZIP entry {} at {start} with method {}",
+            entry.path, entry.method
+        );
+        let compressed = StreamReader::new(
+            self.stream_data(data, Some(range))
+                .await?
+                .map_err(io::Error::other),
+        );
+        let reader: Pin<Box<dyn AsyncRead + Send>> = match entry.method {
+            0 => Box::pin(compressed),
+            8 => Box::pin(DeflateDecoder::new(compressed)),
+            method => {
+                return Err(Error::BadArchive(format!(
+                    "{} uses the unsupported compression method {method}",
+                    entry.path
+                )));
+            }
+        };
+        Ok(ReaderStream::new(LimitedReader::new(
+            reader,
+            entry.size,
+            Some((entry.size, entry.crc32)),
+        ))
+        .map_err(Error::from)
+        .boxed())
+    }
+}
+
+/// How large data that takes up `compressed` bytes may be once decompressed
+fn decompression_limit(compressed: u64) -> u64 {
+    compressed
+        .saturating_mul(MAX_COMPRESSION_RATIO)
+        .max(MIN_DECOMPRESSION_LIMIT)
+}
+
+/// Get back the [`Error`] of a [`LimitedReader`] from where it had to be an [`io::Error`]
+fn archive_error(e: io::Error) -> Error {
+    if !e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+        return e.into();
+    }
+    *e.into_inner()
+        .and_then(|inner| inner.downcast::<Error>().ok())
+        .expect("the inner error was checked")
+}
+
+fn too_many_entries() -> Error {
+    Error::ArchiveLimitExceeded(format!("more than {MAX_ARCHIVE_ENTRIES} entries"))
+}
+
+fn bad_zip(what: &str) -> Error {
+    Error::BadArchive(format!("not a valid ZIP file: {what}"))
+}
+
+/// The `length` bytes at `start` of a ZIP file of `size` bytes, whose values can't be trusted
+fn zip_range(start: u64, length: u64, size: u64) -> Result<Range<u64>, Error> {
+    start
+        .checked_add(length)
+        .filter(|end| *end <= size)
+        .map(|end| start..end)
+        .ok_or_else(|| bad_zip("an offset is out of range"))
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().expect("slice of 4 bytes"))
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().expect("slice of 8 bytes"))
+}
+
+/// Find the central directory from the `tail` of a ZIP file, which starts at `tail_start`
+fn find_central_directory(tail: &[u8], tail_start: u64) -> Result<CentralDirectory, Error> {
+    let eocd = (0..=tail.len().saturating_sub(ZIP_EOCD_SIZE))
+        .rev()
+        .find(|&at| tail.len() >= ZIP_EOCD_SIZE && read_u32(tail, at) == ZIP_EOCD_SIGNATURE)
+        .ok_or_else(|| bad_zip("no end of central directory"))?;
+    let entries = read_u16(tail, eocd + 10);
+    let size = read_u32(tail, eocd + 12);
+    let offset = read_u32(tail, eocd + 16);
+    if entries == u16::MAX || size == u32::MAX || offset == u32::MAX {
+        let locator = eocd
+            .checked_sub(ZIP64_EOCD_LOCATOR_SIZE)
+            .filter(|&at| read_u32(tail, at) == ZIP64_EOCD_LOCATOR_SIGNATURE)
+            .ok_or_else(|| bad_zip("no ZIP64 end of central directory locator"))?;
+        return Ok(CentralDirectory::Zip64(read_u64(tail, locator + 8)));
+    }
+    let range = offset as u64..offset as u64 + size as u64;
+    if range.end > tail_start + eocd as u64 {
+        return Err(bad_zip("the central directory is after its end"));
+    }
+    Ok(CentralDirectory::At {
+        range,
+        entries: entries as u64,
+    })
+}
+
+/// The range of the central directory and the number of entries from the ZIP64 end of central
+/// directory record
+fn parse_zip64_eocd(record: &[u8]) -> Result<(Range<u64>, u64), Error> {
+    if record.len() < ZIP64_EOCD_SIZE || read_u32(record, 0) != ZIP64_EOCD_SIGNATURE {
+        return Err(bad_zip("no ZIP64 end of central directory"));
+    }
+    let entries = read_u64(record, 32);
+    let size = read_u64(record, 40);
+    let offset = read_u64(record, 48);
+    let end = offset
+        .checked_add(size)
+        .ok_or_else(|| bad_zip("the central directory is out of range"))?;
+    Ok((offset..end, entries))
+}
+
+/// Parse `count` entries from the central `directory` of a ZIP file of `file_size` bytes
+fn parse_central_directory(
+    directory: &[u8],
+    count: u64,
+    file_size: u64,
+) -> Result<Vec<ZipEntry>, Error> {
+    let mut entries = Vec::new();
+    let mut at = 0;
+    for _ in 0..count {
+        let header = directory
+            .get(at..at + ZIP_CENTRAL_HEADER_SIZE)
+            .filter(|header| read_u32(header, 0) == ZIP_CENTRAL_HEADER_SIGNATURE)
+            .ok_or_else(|| bad_zip("truncated central directory"))?;
+        let flags = read_u16(header, 8);
+        let name_length = read_u16(header, 28) as usize;
+        let extra_length = read_u16(header, 30) as usize;
+        let comment_length = read_u16(header, 32) as usize;
+        let name_start = at + ZIP_CENTRAL_HEADER_SIZE;
+        let extra_start = name_start + name_length;
+        let end = extra_start + extra_length + comment_length;
+        let (name, extra) = match (
+            directory.get(name_start..extra_start),
+            directory.get(extra_start..extra_start + extra_length),
+        ) {
+            (Some(name), Some(extra)) if end <= directory.len() => (name, extra),
+            _ => return Err(bad_zip("truncated central directory")),
+        };
+
+        let mut compressed_size = read_u32(header, 20) as u64;
+        let mut size = read_u32(header, 24) as u64;
+        let mut local_header_offset = read_u32(header, 42) as u64;
+        // values that don't fit are in the ZIP64 extra field, in this order
+        if let Some(mut zip64) = zip64_extra_field(extra) {
+            for value in [&mut size, &mut compressed_size, &mut local_header_offset] {
+                if *value == u32::MAX as u64 {
+                    if zip64.len() < 8 {
+                        return Err(bad_zip("truncated ZIP64 extra field"));
+                    }
+                    *value = read_u64(zip64, 0);
+                    zip64 = &zip64[8..];
+                }
+            }
+        }
+        // the local header and the data of the entry must be in the file
+        zip_range(
+            local_header_offset,
+            (ZIP_LOCAL_HEADER_SIZE as u64).saturating_add(compressed_size),
+            file_size,
+        )?;
+
+        entries.push(ZipEntry {
+            path: String::from_utf8_lossy(name).into_owned(),
+            method: read_u16(header, 10),
+            encrypted: flags & 1 != 0,
+            crc32: read_u32(header, 16),
+            compressed_size,
+            size,
+            time_modified: dos_time(read_u16(header, 14), read_u16(header, 12)),
+            local_header_offset,
+        });
+        at = end;
+    }
+    Ok(entries)
+}
+
+/// The data of the ZIP64 extra field in `extra`
+fn zip64_extra_field(mut extra: &[u8]) -> Option<&[u8]> {
+    while extra.len() >= 4 {
+        let (id, length) = (read_u16(extra, 0), read_u16(extra, 2) as usize);
+        let data = extra.get(4..4 + length)?;
+        if id == ZIP64_EXTRA_FIELD {
+            return Some(data);
+        }
+        extra = &extra[4 + length..];
+    }
+    None
+}
+
+/// A date and time in the MS-DOS format of ZIP files
+fn dos_time(date: u16, time: u16) -> Option<NaiveDateTime> {
+    NaiveDate::from_ymd_opt(
+        1980 + (date >> 9) as i32,
+        ((date >> 5) & 0xf) as u32,
+        (date & 0x1f) as u32,
+    )?
+    .and_hms_opt(
+        (time >> 11) as u32,
+        ((time >> 5) & 0x3f) as u32,
+        ((time & 0x1f) * 2) as u32,
+    )
+}
+
+/// Fails once more than `limit` bytes are read from `inner`
+///
+/// With `expected`, the data must also have exactly that size and CRC-32 checksum.
+struct LimitedReader<R> {
+    inner: R,
+    limit: u64,
+    read: u64,
+    expected: Option<(u64, u32)>,
+    crc: crc32fast::Hasher,
+}
+
+impl<R> LimitedReader<R> {
+    fn new(inner: R, limit: u64, expected: Option<(u64, u32)>) -> Self {
+        Self {
+            inner,
+            limit,
+            read: 0,
+            expected,
+            crc: crc32fast::Hasher::new(),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        if let Err(e) = std::task::ready!(Pin::new(&mut self.inner).poll_read(cx, buf)) {
+            return Poll::Ready(Err(e));
+        }
+        let new = &buf.filled()[before..];
+        self.read += new.len() as u64;
+        if self.read > self.limit {
+            return Poll::Ready(Err(io::Error::other(Error::ArchiveLimitExceeded(format!(
+                "more than {} bytes after decompressing",
+                self.limit
+            )))));
+        }
+        if let Some((size, crc32)) = self.expected {
+            if new.is_empty() && (self.read != size || self.crc.clone().finalize() != crc32) {
+                return Poll::Ready(Err(io::Error::other(Error::BadArchive(
+                    "the entry does not match its checksum".to_string(),
+                ))));
+            }
+            self.crc.update(new);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_zip::base::write::ZipFileWriter;
+    use async_zip::{Compression, ZipEntryBuilder};
+
+    use super::*;
+
+    #[test]
+    fn test_archive_kind() {
+        assert_eq!(ArchiveKind::from_name("a.ZIP"), Some(ArchiveKind::Zip));
+        assert_eq!(ArchiveKind::from_name("a.tar.gz"), Some(ArchiveKind::TarGz));
+        assert_eq!(ArchiveKind::from_name("a.tgz"), Some(ArchiveKind::TarGz));
+        assert_eq!(ArchiveKind::from_name("a.tar"), Some(ArchiveKind::Tar));
+        assert_eq!(ArchiveKind::from_name("a.gz"), None);
+        assert_eq!(ArchiveKind::from_name("zip"), None);
+    }
+
+    #[tokio::test]
+    async fn test_zip_central_directory() {
+        let mut zip = ZipFileWriter::new(Vec::new());
+        zip.write_entry_whole(
+            ZipEntryBuilder::new("docs/".to_string().into(), Compression::Stored),
+            &[],
+        )
+        .await
+        .unwrap();
+        zip.write_entry_whole(
+            ZipEntryBuilder::new("docs/a.txt".to_string().into(), Compression::Deflate),
+            &b"a".repeat(1000),
+        )
+        .await
+        .unwrap();
+        let zip = zip.close().await.unwrap();
+
+        let CentralDirectory::At { range, entries } = find_central_directory(&zip, 0).unwrap()
+        else {
+            panic!("no ZIP64 needed");
+        };
+        let directory = &zip[range.start as usize..];
+        let size = zip.len() as u64;
+        let entries = parse_central_directory(directory, entries, size).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].is_dir());
+        assert_eq!(entries[1].path, "docs/a.txt");
+        assert_eq!(entries[1].method, 8);
+        assert_eq!(entries[1].size, 1000);
+        assert!(entries[1].compressed_size < 1000);
+        assert_eq!(entries[1].crc32, crc32fast::hash(&b"a".repeat(1000)));
+
+        assert!(find_central_directory(b"not a zip file at all", 0).is_err());
+        assert!(parse_central_directory(directory, 3, size).is_err());
+        // entries that claim more data than the file has
+        assert!(parse_central_directory(directory, 2, range.start / 2).is_err());
+        assert!(matches!(
+            zip_range(u64::MAX - 10, entries[1].compressed_size, u64::MAX),
+            Err(Error::BadArchive(_))
+        ));
+        assert!(zip_range(range.start, 10, size).is_ok());
+        assert!(zip_range(range.start, range.end, size).is_err());
+    }
+}
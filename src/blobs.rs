@@ -0,0 +1,268 @@
+//! Content addressed storage of file data.
+//!
+//! The data of a file is stored as a blob under [`storage::key_blob`], named by its SHA-256.
+//! Files with the same content share one blob, each with its own id, name and expiration. The
+//! `blob` table counts how many files reference a blob, and the blob is deleted together with
+//! the last of them.
+//!
+//! A new file is committed in two steps: its blob is prepared, storing the data if it is new,
+//! then the reference to the blob and the file are added to the DB in one transaction. If
+//! anything goes wrong before the transaction is committed, the file does not exist and a new
+//! blob is not referenced by anything.
+//!
+//! Files from before blobs were introduced have no `blob` in their DB entry, their data is
+//! still stored below [`storage::key_fid`].
+
+use std::path::Path;
+
+use log::{debug, info, warn};
+use rand::distr::SampleString as _;
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    ActiveModelTrait as _, ColumnTrait as _, DatabaseTransaction, EntityTrait as _,
+    ModelTrait as _, PaginatorTrait as _, QueryFilter as _,
+};
+use tokio::sync::MutexGuard;
+
+use crate::compression::StoredData;
+use crate::db::schema::blob::{self, Column as BlobC, Entity as BlobE};
+use crate::db::schema::file::{Column as FileC, Entity as FileE};
+use crate::errors::Error;
+use crate::files::FileID;
+use crate::state::AppState;
+use crate::storage;
+use crate::user::User;
+
+/// Where the data of a new blob comes from
+#[derive(Debug, Clone, Copy)]
+pub enum BlobSource<'a> {
+    /// An object in the storage
+    Stored(&'a str),
+    /// A local file
+    Local(&'a Path),
+}
+
+/// A blob that is stored and can be referenced by a new file, from [`AppState::prepare_blob`]
+///
+/// Until it is referenced or discarded, no other blob can be released, and no other blob can be
+/// checked for or moved into place.
+#[derive(Debug)]
+pub struct PreparedBlob<'a> {
+    sha256: String,
+    /// DB entry of the blob if it was stored for the new file, `None` if it already existed
+    new: Option<blob::ActiveModel>,
+    _guard: MutexGuard<'a, ()>,
+}
+
+impl PreparedBlob<'_> {
+    /// Add the reference of the new file to the blob, in the transaction that creates the file
+    pub async fn reference(&self, txn: &DatabaseTransaction) -> Result<(), Error> {
+        match &self.new {
+            Some(new) => {
+                new.clone().insert(txn).await?;
+            }
+            None => {
+                BlobE::update_many()
+                    .col_expr(
+                        BlobC::ReferenceCount,
+                        Expr::col(BlobC::ReferenceCount).add(1),
+                    )
+                    .filter(BlobC::Sha256.eq(&self.sha256))
+                    .exec(txn)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AppState {
+    /// Make sure the blob `sha256` is stored, storing the data from `source` as the blob if
+    /// there is none yet
+    ///
+    /// The blob is not referenced yet, see [`PreparedBlob::reference`]. `source` may be moved
+    /// into the blob, whatever is left of it is up to the caller.
+    pub async fn prepare_blob(
+        &self,
+        sha256: &str,
+        size: u64,
+        source: BlobSource<'_>,
+    ) -> Result<PreparedBlob<'_>, Error> {
+        let guard = self.blob_lock.lock().await;
+        if BlobE::find_by_id(sha256).one(self.db()).await?.is_some() {
+            return Ok(self.share_blob(sha256, guard));
+        }
+        drop(guard);
+
+        // storing takes long, so other blobs can be prepared in the meantime
+        let compression = self.compression_for(source).await?;
+        let data_key = self.new_data_key().await?;
+        let staged = match (source, compression, &data_key) {
+            (BlobSource::Stored(from), None, None) => from.to_string(),
+            (source, compression, data_key) => {
+                let nonce = rand::distr::Alphanumeric.sample_string(&mut *self.csprng().await, 16);
+                let staged = storage::key_staged_blob(sha256, &nonce);
+                debug!("Storing new blob {sha256} from {source:?} as {staged}");
+                let stored = match (source, compression, data_key) {
+                    (BlobSource::Local(path), None, None) => {
+                        self.storage().put(&staged, path).await
+                    }
+                    (source, compression, data_key) => {
+                        self.store_encoded(
+                            source,
+                            &staged,
+                            compression,
+                            data_key.as_ref().map(|k| &k.key),
+                        )
+                        .await
+                    }
+                };
+                if let Err(e) = stored {
+                    if let Err(e) = self.storage().delete(&staged).await {
+                        warn!("Could not delete the partially stored blob {staged}: {e}");
+                    }
+                    return Err(e);
+                }
+                staged
+            }
+        };
+
+        let guard = self.blob_lock.lock().await;
+        // the same data may have been stored by another upload in the meantime
+        if BlobE::find_by_id(sha256).one(self.db()).await?.is_some() {
+            if !matches!(source, BlobSource::Stored(from) if from == staged) {
+                self.storage().delete(&staged).await?;
+            }
+            return Ok(self.share_blob(sha256, guard));
+        }
+        let key = storage::key_blob(sha256);
+        debug!("Moving new blob {staged} to {key}");
+        self.storage().rename(&staged, &key).await?;
+        if let Some(compression) = compression {
+            info!(
+                "Compressed blob {sha256} with {} from {size} to {} bytes",
+                compression.name(),
+                self.storage().stat(&key).await?.size
+            );
+        }
+        Ok(PreparedBlob {
+            sha256: sha256.to_string(),
+            new: Some(blob::ActiveModel {
+                sha256: sea_orm::ActiveValue::Set(sha256.to_string()),
+                size: sea_orm::ActiveValue::Set(size as i64),
+                reference_count: sea_orm::ActiveValue::Set(1),
+                compression: sea_orm::ActiveValue::Set(compression.map(|c| c.name().to_string())),
+                master_key_id: sea_orm::ActiveValue::Set(
+                    data_key.as_ref().map(|k| k.master_key_id.clone()),
+                ),
+                wrapped_key: sea_orm::ActiveValue::Set(data_key.map(|k| k.wrapped)),
+            }),
+            _guard: guard,
+        })
+    }
+
+    /// A new reference to the blob `sha256`, which already exists
+    fn share_blob<'a>(&self, sha256: &str, guard: MutexGuard<'a, ()>) -> PreparedBlob<'a> {
+        info!("Data is already stored in blob {sha256}, sharing it");
+        PreparedBlob {
+            sha256: sha256.to_string(),
+            new: None,
+            _guard: guard,
+        }
+    }
+
+    /// Undo [`prepare_blob`](Self::prepare_blob) if the new file could not be created
+    pub async fn discard_blob(&self, blob: PreparedBlob<'_>) -> Result<(), Error> {
+        if blob.new.is_some() {
+            debug!("Deleting blob {} that was stored for nothing", blob.sha256);
+            self.storage()
+                .delete(&storage::key_blob(&blob.sha256))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Remove a reference to the blob `sha256` in `txn`, which removes the file that referenced it
+    ///
+    /// If it was the last reference, the DB entry of the blob is deleted too and its size is
+    /// returned. The caller deletes the data of the blob once `txn` is committed, and holds the
+    /// blob lock until then.
+    pub async fn release_blob(
+        &self,
+        sha256: &str,
+        txn: &DatabaseTransaction,
+    ) -> Result<Option<u64>, Error> {
+        BlobE::update_many()
+            .col_expr(
+                BlobC::ReferenceCount,
+                Expr::col(BlobC::ReferenceCount).sub(1),
+            )
+            .filter(BlobC::Sha256.eq(sha256))
+            .exec(txn)
+            .await?;
+        let Some(blob) = BlobE::find_by_id(sha256).one(txn).await? else {
+            warn!("Released blob {sha256} that has no DB entry");
+            return Ok(None);
+        };
+        if blob.reference_count > 0 {
+            debug!(
+                "Blob {sha256} is still referenced {} times",
+                blob.reference_count
+            );
+            return Ok(None);
+        }
+
+        info!("Last reference to blob {sha256} is gone, deleting it");
+        let size = blob.size as u64;
+        blob.delete(txn).await?;
+        Ok(Some(size))
+    }
+
+    /// Does a file of `user`, or any file if `None`, reference the blob `sha256`?
+    pub async fn blob_referenced(&self, sha256: &str, user: Option<&User>) -> Result<bool, Error> {
+        let mut query = FileE::find().filter(FileC::Blob.eq(sha256));
+        if let Some(user) = user {
+            query = query.filter(FileC::UserId.eq(user.id()));
+        }
+        Ok(query.count(self.db()).await? > 0)
+    }
+
+    /// Where and how the data of `blob` is stored
+    pub fn blob_data(&self, blob: &blob::Model) -> Result<StoredData, Error> {
+        Ok(StoredData {
+            key: storage::key_blob(&blob.sha256),
+            size: blob.size as u64,
+            compression: blob.compression.as_deref().map(str::parse).transpose()?,
+            data_key: match (&blob.master_key_id, &blob.wrapped_key) {
+                (Some(id), Some(wrapped)) => Some(self.unwrap_data_key(id, wrapped)?),
+                _ => None,
+            },
+        })
+    }
+
+    /// Where the data of the file `fid` named `name` is stored
+    ///
+    /// Fails with [`Error::FileNotFound`] if `fid` is stored in a blob under another name.
+    pub async fn stored_data(&self, fid: FileID, name: &str) -> Result<StoredData, Error> {
+        if let Some(file) = self.get_file_db_entry(fid, self.db()).await?
+            && let Some(sha256) = file.blob
+        {
+            if file.name.as_deref() != Some(name) {
+                return Err(Error::FileNotFound);
+            }
+            let Some(blob) = BlobE::find_by_id(&sha256).one(self.db()).await? else {
+                warn!("File {fid} references blob {sha256} that has no DB entry");
+                return Err(Error::FileNotFound);
+            };
+            return self.blob_data(&blob);
+        }
+        let key = storage::key_fid_datafile(fid, name);
+        let size = self.storage().stat(&key).await?.size;
+        Ok(StoredData {
+            key,
+            size,
+            compression: None,
+            data_key: None,
+        })
+    }
+}
@@ -0,0 +1,259 @@
+//! Several files that were uploaded together
+//!
+//! Uploading more than one file in one form puts them into a collection, which has its own page
+//! listing all of them. The files of a collection expire together, and deleting the collection
+//! deletes all of them. Everything else, like the download limit and the password, applies to
+//! each file on its own, so a collection only lists the protected files that whoever looks at it
+//! may access.
+
+use actix_session::Session;
+use chrono::NaiveDateTime;
+use log::info;
+use rand::distr::SampleString;
+use sea_orm::{
+    ActiveModelTrait as _, ColumnTrait as _, EntityTrait as _, ModelTrait as _, QueryFilter as _,
+};
+use serde::Serialize;
+
+use crate::db::schema::collection::{self, Entity as CollectionE, Model as CollectionM};
+use crate::db::schema::file::{self, Column as FileC, Entity as FileE, Model as FileM};
+use crate::errors::Error;
+use crate::file_list::FileSummary;
+use crate::files::{FileID, ser_uploader};
+use crate::state::AppState;
+use crate::storage;
+use crate::upload::ReceivedData;
+use crate::user::User;
+
+/// Most files that can be uploaded together
+pub const MAX_COLLECTION_FILES: usize = 100;
+const COLLECTION_ID_LEN: usize = 12;
+
+#[derive(Debug, Serialize)]
+pub struct CollectionInfos {
+    pub id: String,
+    pub url_frontend: String,
+    pub url_api: String,
+    /// Download of all files as a ZIP, add `?format=tar.gz` for a tarball
+    pub url_archive: String,
+    #[serde(serialize_with = "ser_uploader")]
+    pub uploader: Option<User>,
+    pub time_created: NaiveDateTime,
+    pub time_expiration: NaiveDateTime,
+    /// Of all files together
+    pub size: u64,
+    /// human readable size
+    pub size_human: String,
+    pub files: Vec<FileSummary>,
+    /// How many password protected files are left out of `files`
+    pub hidden_files: usize,
+}
+
+impl AppState {
+    /// Create a collection of uploads that were received with
+    /// [`receive_upload_form`](Self::receive_upload_form)
+    ///
+    /// Each file is committed like a single upload, and all of them are removed again if any
+    /// fails. A slug can't be given to several files.
+    pub async fn commit_collection(
+        &self,
+        uploads: &[(FileID, String, ReceivedData)],
+        user: Option<&User>,
+        options: &crate::files::UploadOptions,
+    ) -> Result<CollectionInfos, Error> {
+        let expiration = match options.slug {
+            Some(_) => Err(Error::SlugForSeveralFiles),
+            None => self
+                .new_file(user, options, 0)
+                .map(|new_file| new_file.expiration),
+        };
+        let collection = match expiration {
+            Ok(expiration) => collection::ActiveModel {
+                id: sea_orm::ActiveValue::Set(self.new_collection_id().await),
+                user_id: sea_orm::ActiveValue::Set(user.map(|u| u.id())),
+                creation_time: sea_orm::ActiveValue::Set(chrono::Utc::now().naive_utc()),
+                expiration_time: sea_orm::ActiveValue::Set(expiration),
+            }
+            .insert(self.db())
+            .await
+            .map_err(Error::from),
+            Err(e) => Err(e),
+        };
+        let collection = match collection {
+            Ok(collection) => collection,
+            Err(e) => {
+                self.discard_uploads(uploads).await?;
+                return Err(e);
+            }
+        };
+
+        info!(
+            "Creating collection {} of {} files",
+            collection.id,
+            uploads.len()
+        );
+        for (i, (fid, name, received)) in uploads.iter().enumerate() {
+            if let Err(e) = self
+                .commit_upload(*fid, name, user, options, received, Some(&collection))
+                .await
+            {
+                // the data of the failed upload is already gone
+                self.discard_uploads(&uploads[i + 1..]).await?;
+                self.remove_collection(collection).await?;
+                return Err(e);
+            }
+        }
+        // the uploader just gave all of them their password
+        let files = self.collection_files(&collection.id).await?;
+        self.collection_infos(collection, files, 0).await
+    }
+
+    /// Remove the staged data of `uploads`
+    pub async fn discard_uploads(
+        &self,
+        uploads: &[(FileID, String, ReceivedData)],
+    ) -> Result<(), Error> {
+        for (fid, _, _) in uploads {
+            self.storage()
+                .delete_all(&storage::key_staging(*fid))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn new_collection_id(&self) -> String {
+        rand::distr::Alphanumeric.sample_string(&mut *self.csprng().await, COLLECTION_ID_LEN)
+    }
+
+    pub async fn get_collection(&self, id: &str) -> Result<CollectionM, Error> {
+        CollectionE::find_by_id(id)
+            .one(self.db())
+            .await?
+            .ok_or(Error::CollectionNotFound)
+    }
+
+    pub async fn collection_files(&self, id: &str) -> Result<Vec<FileM>, Error> {
+        Ok(FileE::find()
+            .filter(FileC::CollectionId.eq(id))
+            .all(self.db())
+            .await?)
+    }
+
+    /// The infos of `collection` for whoever makes this request
+    ///
+    /// Files that can't be accessed with `password`, see
+    /// [`check_file_access`](Self::check_file_access), are only counted.
+    pub async fn make_collection_infos(
+        &self,
+        collection: CollectionM,
+        password: Option<&str>,
+        session: &Session,
+        user: Option<&User>,
+    ) -> Result<CollectionInfos, Error> {
+        let mut accessible = Vec::new();
+        let mut hidden = 0;
+        for file in self.collection_files(&collection.id).await? {
+            match self
+                .check_file_access(file.id.into(), password, session, user)
+                .await
+            {
+                Ok(()) => accessible.push(file),
+                Err(Error::FilePasswordRequired | Error::WrongFilePassword) => hidden += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        self.collection_infos(collection, accessible, hidden).await
+    }
+
+    async fn collection_infos(
+        &self,
+        collection: CollectionM,
+        members: Vec<FileM>,
+        hidden_files: usize,
+    ) -> Result<CollectionInfos, Error> {
+        let uploader = match collection.user_id {
+            Some(uid) => Some(User::get_by_id(uid, self.db()).await?),
+            None => None,
+        };
+        let mut files = Vec::new();
+        for file in members {
+            files.push(self.file_summary(file).await?);
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let size = files.iter().map(|file| file.size).sum();
+        Ok(CollectionInfos {
+            url_frontend: self.uri_frontend_collection_id(&collection.id).to_string(),
+            url_api: self.uri_api_collection_id(&collection.id).to_string(),
+            url_archive: self
+                .uri_api_collection_id_archive(&collection.id)
+                .to_string(),
+            id: collection.id,
+            uploader,
+            time_created: collection.creation_time,
+            time_expiration: collection.expiration_time,
+            size,
+            size_human: human_bytes::human_bytes(size as f64),
+            files,
+            hidden_files,
+        })
+    }
+
+    /// Remove the collection `id` of `user` with all of its files
+    pub async fn delete_collection(&self, user: &User, id: &str) -> Result<(), Error> {
+        let collection = self.get_collection(id).await?;
+        if collection.user_id != Some(user.id()) {
+            return Err(Error::Unauthorized);
+        }
+        info!("Deleting collection: {id}");
+        self.remove_collection(collection).await
+    }
+
+    /// Remove `collection` with all of its files
+    pub async fn remove_collection(&self, collection: CollectionM) -> Result<(), Error> {
+        for file in self.collection_files(&collection.id).await? {
+            self.remove_file(file).await?;
+        }
+        collection.delete(self.db()).await?;
+        Ok(())
+    }
+
+    /// Let the collection `id` and all of its files expire at `expiration`
+    pub async fn set_collection_expiration(
+        &self,
+        id: &str,
+        expiration: NaiveDateTime,
+    ) -> Result<(), Error> {
+        info!("Changing the expiration of collection {id} to {expiration}");
+        FileE::update_many()
+            .col_expr(FileC::ExpirationTime, expiration.into())
+            .filter(FileC::CollectionId.eq(id))
+            .exec(self.db())
+            .await?;
+        let mut collection: collection::ActiveModel = self.get_collection(id).await?.into();
+        collection.expiration_time = sea_orm::ActiveValue::Set(expiration);
+        collection.update(self.db()).await?;
+        Ok(())
+    }
+
+    /// Remove collections that expired or whose files were all removed
+    ///
+    /// A collection is empty while its files are still being committed, so empty collections
+    /// are only removed once they are older than the grace period of the scrub.
+    pub async fn clear_expired_collections(&self) -> Result<(), Error> {
+        let now = chrono::Utc::now().naive_utc();
+        let cutoff = now - self.config().files.scrub.grace_period.0;
+        for collection in CollectionE::find().all(self.db()).await? {
+            let empty = collection.creation_time < cutoff
+                && collection
+                    .find_related(file::Entity)
+                    .one(self.db())
+                    .await?
+                    .is_none();
+            if empty || collection.expiration_time < now {
+                info!("Collection has expired: {}", collection.id);
+                self.remove_collection(collection).await?;
+            }
+        }
+        Ok(())
+    }
+}
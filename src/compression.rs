@@ -0,0 +1,182 @@
+//! Transparent compression of blobs at rest.
+//!
+//! When a new blob is stored, the content type of its data is detected with libmagic. If
+//! `files.compression` applies to it, the blob is compressed with zstd. Its `size` stays the
+//! size of the original data, which is what downloads get, unless the client accepts the
+//! compressed data as its `Content-Encoding`.
+
+use std::ops::Range;
+use std::str::FromStr;
+
+use actix_web::HttpRequest;
+use actix_web::http::header;
+use async_compression::Level;
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use bytes::Bytes;
+use futures_util::{StreamExt, TryStreamExt};
+use log::debug;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::blobs::BlobSource;
+use crate::encryption::{self, DataKey};
+use crate::errors::Error;
+use crate::state::{AppState, CONTENT_TYPE_DETECTION_LENGTH, detect_content_type};
+use crate::storage::ByteStream;
+
+/// How the data of a blob is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+}
+
+/// Where and how the data of a file is stored
+#[derive(Debug, Clone)]
+pub struct StoredData {
+    pub key: String,
+    /// Size of the original data, not of the stored object
+    pub size: u64,
+    pub compression: Option<Compression>,
+    /// Key of the stored object, if it is encrypted
+    pub data_key: Option<DataKey>,
+}
+
+impl Compression {
+    /// Name in the DB and in the `Content-Encoding` header
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" => Ok(Self::Zstd),
+            other => Err(Error::UnknownCompression(other.to_string())),
+        }
+    }
+}
+
+impl AppState {
+    /// How a new blob with the data from `source` should be compressed
+    pub async fn compression_for(
+        &self,
+        source: BlobSource<'_>,
+    ) -> Result<Option<Compression>, Error> {
+        let config = &self.config().files.compression;
+        if !config.enabled {
+            return Ok(None);
+        }
+        let content_type = self.source_content_type(source).await?;
+        let compress = config.applies_to(&content_type);
+        debug!("New blob has content type {content_type}, compressing it: {compress}");
+        Ok(compress.then_some(Compression::Zstd))
+    }
+
+    /// The content type of the data from `source`, detected with libmagic
+    pub async fn source_content_type(&self, source: BlobSource<'_>) -> Result<mime::Mime, Error> {
+        let head = match source {
+            BlobSource::Stored(key) => {
+                self.storage()
+                    .get(key, Some(0..CONTENT_TYPE_DETECTION_LENGTH))
+                    .await?
+            }
+            BlobSource::Local(path) => {
+                let mut head = Vec::new();
+                tokio::fs::File::open(path)
+                    .await?
+                    .take(CONTENT_TYPE_DETECTION_LENGTH)
+                    .read_to_end(&mut head)
+                    .await?;
+                head.into()
+            }
+        };
+        detect_content_type(&head)
+    }
+
+    /// Store the data from `source` at `key` with `compression` and encrypted with `data_key`
+    pub async fn store_encoded(
+        &self,
+        source: BlobSource<'_>,
+        key: &str,
+        compression: Option<Compression>,
+        data_key: Option<&DataKey>,
+    ) -> Result<(), Error> {
+        let mut data = match source {
+            BlobSource::Stored(from) => self.storage().stream(from, None).await?,
+            BlobSource::Local(path) => ReaderStream::new(tokio::fs::File::open(path).await?)
+                .map_err(Error::from)
+                .boxed(),
+        };
+        if let Some(compression) = compression {
+            let reader = StreamReader::new(data.map_err(std::io::Error::other));
+            let level = Level::Precise(self.config().files.compression.level);
+            data = match compression {
+                Compression::Zstd => ReaderStream::new(ZstdEncoder::with_quality(reader, level)),
+            }
+            .map_err(Error::from)
+            .boxed();
+        }
+        if let Some(data_key) = data_key {
+            data = encryption::encrypt(data, data_key.clone());
+        }
+        self.storage().put_stream(key, data).await
+    }
+
+    /// Stream the original data, or only `range` of it
+    pub async fn stream_data(
+        &self,
+        data: &StoredData,
+        range: Option<Range<u64>>,
+    ) -> Result<ByteStream, Error> {
+        let Some(compression) = data.compression else {
+            return self.stream_stored(data, range).await;
+        };
+        let stored = self.stream_stored(data, None).await?;
+        let stored = StreamReader::new(stored.map_err(std::io::Error::other));
+        let mut reader = match compression {
+            Compression::Zstd => ZstdDecoder::new(stored),
+        };
+        let range = range.unwrap_or(0..data.size);
+        // compressed data can only be read from the start
+        tokio::io::copy(&mut (&mut reader).take(range.start), &mut tokio::io::sink()).await?;
+        let length = range.end.min(data.size).saturating_sub(range.start);
+        Ok(ReaderStream::new(reader.take(length))
+            .map_err(Error::from)
+            .boxed())
+    }
+
+    /// Like [`stream_data`](Self::stream_data), but reads it into memory
+    pub async fn read_data(
+        &self,
+        data: &StoredData,
+        range: Option<Range<u64>>,
+    ) -> Result<Bytes, Error> {
+        let chunks: Vec<Bytes> = self.stream_data(data, range).await?.try_collect().await?;
+        Ok(chunks.concat().into())
+    }
+}
+
+/// Does the client of `req` accept `compression` as the `Content-Encoding`?
+pub fn accepts_encoding(req: &HttpRequest, compression: Compression) -> bool {
+    let Some(accepted) = req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    accepted.split(',').any(|item| {
+        let mut params = item.split(';').map(str::trim);
+        let encoding = params.next().unwrap_or_default();
+        let quality = params
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        encoding.eq_ignore_ascii_case(compression.name()) && quality > 0.0
+    })
+}
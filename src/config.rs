@@ -1,11 +1,13 @@
+use std::fmt::Display;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::str::FromStr;
 
 use actix_web::web;
-use log::trace;
 use serde::{Deserialize, Serialize};
 
-use crate::errors::ConfigError;
+use crate::errors::{ConfigError, Error};
+use crate::user::UserKind;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
@@ -23,6 +25,147 @@ pub struct FilesConfig {
     pub storage_dir: String,
     pub default_expiration_days: u64,
     pub delete_old_files: bool,
+    /// How long uploaders may keep their files
+    #[serde(default)]
+    pub expiration: ExpirationConfig,
+    /// Which files are deleted first if `delete_old_files` is set
+    #[serde(default)]
+    pub eviction_policy: EvictionPolicy,
+    /// Where the uploaded data is kept
+    #[serde(default)]
+    pub storage_backend: StorageBackendKind,
+    /// Only needed for [`StorageBackendKind::S3`]
+    pub s3: Option<S3Config>,
+    /// Unfinished resumable uploads are kept here, always on the local filesystem
+    #[serde(default = "default_staging_dir")]
+    pub staging_dir: String,
+    /// How long an unfinished resumable upload is kept without progress
+    #[serde(default = "default_partial_upload_expiration")]
+    pub partial_upload_expiration: HumanDuration,
+    /// Also compute BLAKE3 checksums of uploads, SHA-256 is always computed
+    #[serde(default)]
+    pub blake3: bool,
+    /// Which files are compressed at rest
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// Whether files are encrypted at rest
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    /// Checking that the DB and the storage agree
+    #[serde(default)]
+    pub scrub: ScrubConfig,
+    /// How many characters new file ids have
+    #[serde(default = "default_id_length")]
+    pub id_length: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackendKind {
+    /// Files are stored in `storage_dir`
+    #[default]
+    Local,
+    /// Files are stored in an S3 compatible bucket
+    S3,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ExpirationConfig {
+    #[serde(default = "default_expiration_anonymous")]
+    pub anonymous: ExpirationLimits,
+    #[serde(default = "default_expiration_standard")]
+    pub standard: ExpirationLimits,
+    #[serde(default = "default_expiration_administrator")]
+    pub administrator: ExpirationLimits,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CompressionConfig {
+    /// Compress new files with zstd if their content type is one of `content_types`
+    #[serde(default)]
+    pub enabled: bool,
+    /// zstd compression level, 1 to 22
+    #[serde(default = "default_compression_level")]
+    pub level: i32,
+    /// Content types like `application/json`, or just the type like `text/` to match all
+    /// of its subtypes
+    #[serde(default = "default_compression_content_types")]
+    pub content_types: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct EncryptionConfig {
+    /// Encrypt new files, needs `master_key`
+    #[serde(default)]
+    pub enabled: bool,
+    /// Secret from which the key that wraps the keys of all files is derived
+    #[serde(skip_serializing)]
+    pub master_key: Option<String>,
+    /// Previous master keys, still needed for files until the master key was rotated
+    #[serde(default, skip_serializing)]
+    pub old_master_keys: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ScrubConfig {
+    /// Scrub the storage with every garbage collection
+    #[serde(default)]
+    pub enabled: bool,
+    /// Fix the problems that are found instead of only reporting them
+    #[serde(default)]
+    pub repair: bool,
+    /// Also hash all stored data and compare it to its checksum, this reads everything
+    #[serde(default)]
+    pub verify_checksums: bool,
+    /// Objects without a file and empty collections are only removed once they are this old,
+    /// uploads in progress don't have a file yet
+    #[serde(default = "default_scrub_grace_period")]
+    pub grace_period: HumanDuration,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct ExpirationLimits {
+    pub min: HumanDuration,
+    pub max: HumanDuration,
+}
+
+/// A duration written like `30m`, `12h` or `7d`, plain numbers are seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HumanDuration(pub chrono::TimeDelta);
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// Delete the files that would expire next
+    #[default]
+    ClosestToExpiration,
+    /// Delete the files that were uploaded first
+    Oldest,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Needed for anything that is not AWS, like MinIO
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub access_key_id: String,
+    #[serde(skip_serializing)]
+    pub secret_access_key: String,
+    /// Allow talking to the endpoint over plain http
+    #[serde(default)]
+    pub allow_http: bool,
+    /// Stored objects get this prefix in the bucket
+    #[serde(default)]
+    pub prefix: String,
+    /// Redirect downloads to a presigned url of the bucket instead of streaming them
+    ///
+    /// Files that are compressed or encrypted at rest or have a download limit are always
+    /// streamed.
+    #[serde(default)]
+    pub presigned_downloads: bool,
+    #[serde(default = "default_presigned_expiration_secs")]
+    pub presigned_expiration_secs: u64,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -56,17 +199,216 @@ impl Config {
         let content = std::fs::read_to_string(path)?;
         Ok(toml::from_str(&content)?)
     }
+}
+
+impl ExpirationConfig {
+    pub fn limits(&self, kind: UserKind) -> ExpirationLimits {
+        match kind {
+            UserKind::Anonymous => self.anonymous,
+            UserKind::Standard => self.standard,
+            UserKind::Admin => self.administrator,
+        }
+    }
+}
+
+impl Default for ExpirationConfig {
+    fn default() -> Self {
+        Self {
+            anonymous: default_expiration_anonymous(),
+            standard: default_expiration_standard(),
+            administrator: default_expiration_administrator(),
+        }
+    }
+}
 
-    pub fn largest_possible_upload(&self) -> usize {
-        let t = 1024
-            * if self.files.max_size_kb_anon < self.files.max_size_kb_users {
-                self.files.max_size_kb_users as usize
-            } else {
-                self.files.max_size_kb_anon as usize
-            };
-        trace!("largest_possible_upload = {t} Bytes");
-        t
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            level: default_compression_level(),
+            content_types: default_compression_content_types(),
+        }
     }
 }
 
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            repair: false,
+            verify_checksums: false,
+            grace_period: default_scrub_grace_period(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Should files of `content_type` be compressed?
+    pub fn applies_to(&self, content_type: &mime::Mime) -> bool {
+        let essence = content_type.essence_str();
+        self.enabled
+            && self
+                .content_types
+                .iter()
+                .any(|ct| match ct.strip_suffix('/') {
+                    Some(type_) => content_type.type_() == type_,
+                    None => essence == ct,
+                })
+    }
+}
+
+impl ExpirationLimits {
+    fn new(min: chrono::TimeDelta, max: chrono::TimeDelta) -> Self {
+        Self {
+            min: HumanDuration(min),
+            max: HumanDuration(max),
+        }
+    }
+
+    pub fn contains(&self, duration: HumanDuration) -> bool {
+        self.min <= duration && duration <= self.max
+    }
+
+    pub fn clamp(&self, duration: HumanDuration) -> HumanDuration {
+        duration.clamp(self.min, self.max)
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+        let number: i64 = number
+            .parse()
+            .map_err(|_| Error::BadDuration(s.to_string()))?;
+        let delta = match unit {
+            "" | "s" => chrono::TimeDelta::try_seconds(number),
+            "m" => chrono::TimeDelta::try_minutes(number),
+            "h" => chrono::TimeDelta::try_hours(number),
+            "d" => chrono::TimeDelta::try_days(number),
+            "w" => chrono::TimeDelta::try_weeks(number),
+            _ => None,
+        };
+        delta
+            .map(HumanDuration)
+            .ok_or_else(|| Error::BadDuration(s.to_string()))
+    }
+}
+
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.0.num_seconds();
+        for (unit, unit_secs) in [("w", 604800), ("d", 86400), ("h", 3600), ("m", 60)] {
+            if secs != 0 && secs % unit_secs == 0 {
+                return write!(f, "{}{unit}", secs / unit_secs);
+            }
+        }
+        write!(f, "{secs}s")
+    }
+}
+
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for HumanDuration {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_string().serialize(serializer)
+    }
+}
+
+fn default_presigned_expiration_secs() -> u64 {
+    300
+}
+
+fn default_id_length() -> usize {
+    8
+}
+
+fn default_staging_dir() -> String {
+    "/tmp/tempfiles-rs/staging".to_string()
+}
+
+fn default_partial_upload_expiration() -> HumanDuration {
+    HumanDuration(chrono::TimeDelta::days(1))
+}
+
+fn default_scrub_grace_period() -> HumanDuration {
+    HumanDuration(chrono::TimeDelta::hours(1))
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+fn default_compression_content_types() -> Vec<String> {
+    [
+        "text/",
+        "application/json",
+        "application/xml",
+        "application/javascript",
+        "image/svg+xml",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_expiration_anonymous() -> ExpirationLimits {
+    ExpirationLimits::new(chrono::TimeDelta::minutes(10), chrono::TimeDelta::days(7))
+}
+
+fn default_expiration_standard() -> ExpirationLimits {
+    ExpirationLimits::new(chrono::TimeDelta::minutes(10), chrono::TimeDelta::days(60))
+}
+
+fn default_expiration_administrator() -> ExpirationLimits {
+    ExpirationLimits::new(chrono::TimeDelta::minutes(10), chrono::TimeDelta::days(365))
+}
+
 pub fn actix_config_global(_cfg: &mut web::ServiceConfig) {}
+
+#[cfg(test)]
+mod test {
+    use super::{CompressionConfig, HumanDuration};
+
+    #[test]
+    fn test_human_duration() {
+        for (raw, secs) in [
+            ("90", 90),
+            ("90s", 90),
+            ("10m", 600),
+            ("2h", 7200),
+            ("7d", 604800),
+        ] {
+            let d: HumanDuration = raw.parse().unwrap();
+            assert_eq!(d.0.num_seconds(), secs);
+            assert_eq!(d.to_string().parse::<HumanDuration>().unwrap(), d);
+        }
+        for bad in ["", "d", "-5m", "5y", "1.5h", "99999999999999999999w"] {
+            assert!(bad.parse::<HumanDuration>().is_err(), "{bad} was parsed");
+        }
+    }
+
+    #[test]
+    fn test_compression_applies_to() {
+        let mut config = CompressionConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        for (ct, compressed) in [
+            ("text/plain; charset=us-ascii", true),
+            ("application/json", true),
+            ("application/octet-stream", false),
+            ("image/png", false),
+        ] {
+            assert_eq!(config.applies_to(&ct.parse().unwrap()), compressed, "{ct}");
+        }
+        config.enabled = false;
+        assert!(!config.applies_to(&mime::TEXT_PLAIN));
+    }
+}
@@ -3,15 +3,30 @@
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::db::types::RawUserID;
+use crate::db::types::{RawFileID, RawUserID};
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
 #[sea_orm(table_name = "file")]
 pub struct Model {
     #[sea_orm(primary_key, unique)]
-    pub id: RawUserID,
+    pub id: RawFileID,
     pub user_id: Option<RawUserID>,
     pub expiration_time: DateTime,
+    pub size: i64,
+    pub creation_time: Option<DateTime>,
+    pub download_count: i32,
+    pub max_downloads: Option<i32>,
+    pub password_hash: Option<String>,
+    pub sha256: Option<String>,
+    pub blake3: Option<String>,
+    pub name: Option<String>,
+    pub blob: Option<String>,
+    pub encrypted: bool,
+    #[sea_orm(unique)]
+    pub slug: Option<String>,
+    pub description: Option<String>,
+    pub collection_id: Option<String>,
+    pub content_type: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -22,6 +37,12 @@ pub enum Relation {
         to = "super::user::Column::Id"
     )]
     User,
+    #[sea_orm(
+        belongs_to = "super::collection::Entity",
+        from = "Column::CollectionId",
+        to = "super::collection::Column::Id"
+    )]
+    Collection,
 }
 
 impl Related<super::user::Entity> for Entity {
@@ -30,4 +51,10 @@ impl Related<super::user::Entity> for Entity {
     }
 }
 
+impl Related<super::collection::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Collection.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
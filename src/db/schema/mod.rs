@@ -2,6 +2,9 @@
 
 pub mod prelude;
 
+pub mod blob;
+pub mod collection;
 pub mod file;
+pub mod partial_upload;
 pub mod user;
 pub mod user_token;
@@ -1,3 +1,4 @@
 pub type RawID = u32;
 pub type RawUserID = RawID;
-pub type RawFileID = RawID;
+/// Big enough for the short ids as well as the old ones, which were `u32`
+pub type RawFileID = i64;
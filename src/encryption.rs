@@ -0,0 +1,443 @@
+//! Encryption of blobs at rest.
+//!
+//! Every blob gets a random data key, which is stored in the `blob` table wrapped by the master
+//! key. The master key is derived from `files.encryption.master_key` and identified by a short
+//! hash, so that blobs can still be read while their keys are wrapped by one of the
+//! `old_master_keys`. `tempfiles-rs rotate-master-key` wraps all data keys with the current
+//! master key again.
+//!
+//! Blobs are encrypted with ChaCha20-Poly1305 in segments of [`SEGMENT_SIZE`] bytes, so that
+//! a range of a blob can be decrypted without reading it from the start. The nonce of a segment
+//! is its index and a flag for the last segment, so segments can't be reordered or cut off
+//! without the decryption failing.
+
+use std::ops::Range;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::{Bytes, BytesMut};
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use futures_util::StreamExt;
+use hkdf::Hkdf;
+use log::{debug, info};
+use rand::Rng;
+use sea_orm::{ActiveModelTrait as _, ColumnTrait as _, EntityTrait as _, QueryFilter as _};
+use sha2::{Digest, Sha256};
+
+use crate::compression::StoredData;
+use crate::db::schema::blob::{self, Column as BlobC, Entity as BlobE};
+use crate::errors::{ConfigError, Error};
+use crate::state::AppState;
+use crate::storage::ByteStream;
+
+/// Bytes of data in one encrypted segment, only the last segment may be shorter
+pub const SEGMENT_SIZE: u64 = 64 * 1024;
+/// Bytes that the authentication tag adds to each segment
+const TAG_SIZE: u64 = 16;
+const ENCRYPTED_SEGMENT_SIZE: u64 = SEGMENT_SIZE + TAG_SIZE;
+const NONCE_SIZE: usize = 12;
+/// Bytes of the hash of a master key that identify it
+const MASTER_KEY_ID_SIZE: usize = 8;
+
+/// Key that encrypts the data of one blob
+#[derive(Clone)]
+pub struct DataKey(Key);
+
+/// A data key for a new blob, and how it is stored in the DB
+#[derive(Debug)]
+pub struct NewDataKey {
+    pub key: DataKey,
+    pub master_key_id: String,
+    pub wrapped: String,
+}
+
+/// Key that wraps the data keys
+struct MasterKey {
+    id: String,
+    key: Key,
+}
+
+impl std::fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+impl DataKey {
+    /// A key for other data that is made from the blob, like its thumbnails
+    ///
+    /// The nonces only depend on the segments, so every object that is encrypted with a key
+    /// needs its own `purpose`, and must not be replaced by different data.
+    pub fn derive(&self, purpose: &str) -> DataKey {
+        let mut key = Key::default();
+        Hkdf::<Sha256>::new(Some(b"tempfiles-rs"), &self.0)
+            .expand(purpose.as_bytes(), &mut key)
+            .expect("32 bytes are a valid length for HKDF-SHA256");
+        DataKey(key)
+    }
+}
+
+impl MasterKey {
+    fn derive(secret: &str) -> Self {
+        let mut key = Key::default();
+        Hkdf::<Sha256>::new(Some(b"tempfiles-rs"), secret.as_bytes())
+            .expand(b"master key", &mut key)
+            .expect("32 bytes are a valid length for HKDF-SHA256");
+        let id = hex::encode(&Sha256::digest(key)[..MASTER_KEY_ID_SIZE]);
+        Self { id, key }
+    }
+
+    /// Encrypt `data_key` for the DB, with a random `nonce`
+    fn wrap(&self, data_key: &DataKey, nonce: [u8; NONCE_SIZE]) -> Result<String, Error> {
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend(
+            ChaCha20Poly1305::new(&self.key)
+                .encrypt(&nonce.into(), data_key.0.as_slice())
+                .map_err(|_| Error::Encryption)?,
+        );
+        Ok(BASE64.encode(wrapped))
+    }
+
+    fn unwrap(&self, wrapped: &str) -> Result<DataKey, Error> {
+        let wrapped = BASE64.decode(wrapped).map_err(|_| Error::Decryption)?;
+        if wrapped.len() < NONCE_SIZE {
+            return Err(Error::Decryption);
+        }
+        let (nonce, encrypted) = wrapped.split_at(NONCE_SIZE);
+        let key = ChaCha20Poly1305::new(&self.key)
+            .decrypt(Nonce::from_slice(nonce), encrypted)
+            .map_err(|_| Error::Decryption)?;
+        if key.len() != Key::default().len() {
+            return Err(Error::Decryption);
+        }
+        Ok(DataKey(*Key::from_slice(&key)))
+    }
+}
+
+impl AppState {
+    fn master_key(&self) -> Result<MasterKey, Error> {
+        self.config()
+            .files
+            .encryption
+            .master_key
+            .as_deref()
+            .map(MasterKey::derive)
+            .ok_or(ConfigError::MissingMasterKey.into())
+    }
+
+    /// The current or an old master key with the id `id`
+    fn master_key_by_id(&self, id: &str) -> Result<MasterKey, Error> {
+        let config = &self.config().files.encryption;
+        config
+            .master_key
+            .iter()
+            .chain(config.old_master_keys.iter())
+            .map(|secret| MasterKey::derive(secret))
+            .find(|key| key.id == id)
+            .ok_or_else(|| Error::UnknownMasterKey(id.to_string()))
+    }
+
+    /// A random data key for a new blob, `None` if encryption is disabled
+    pub async fn new_data_key(&self) -> Result<Option<NewDataKey>, Error> {
+        if !self.config().files.encryption.enabled {
+            return Ok(None);
+        }
+        let master_key = self.master_key()?;
+        let (key, nonce) = {
+            let mut rng = self.csprng().await;
+            let mut key = Key::default();
+            rng.fill(key.as_mut_slice());
+            (DataKey(key), rng.random())
+        };
+        let wrapped = master_key.wrap(&key, nonce)?;
+        Ok(Some(NewDataKey {
+            key,
+            master_key_id: master_key.id,
+            wrapped,
+        }))
+    }
+
+    /// Unwrap the data key of a blob that was wrapped by the master key `master_key_id`
+    pub fn unwrap_data_key(&self, master_key_id: &str, wrapped: &str) -> Result<DataKey, Error> {
+        self.master_key_by_id(master_key_id)?.unwrap(wrapped)
+    }
+
+    /// Wrap the data keys of all blobs with the current master key
+    ///
+    /// Afterwards, the old master keys are no longer needed. Returns how many keys were
+    /// wrapped again.
+    pub async fn rotate_master_key(&self) -> Result<u64, Error> {
+        let master_key = self.master_key()?;
+        info!("Rotating to master key {}", master_key.id);
+        let mut rotated = 0;
+        for blob in BlobE::find()
+            .filter(BlobC::MasterKeyId.ne(&master_key.id))
+            .all(self.db())
+            .await?
+        {
+            let (Some(old_id), Some(wrapped)) = (&blob.master_key_id, &blob.wrapped_key) else {
+                continue;
+            };
+            debug!("Wrapping the key of blob {} again", blob.sha256);
+            let key = self.unwrap_data_key(old_id, wrapped)?;
+            let nonce = self.csprng().await.random();
+            let mut blob: blob::ActiveModel = blob.into();
+            blob.master_key_id = sea_orm::ActiveValue::Set(Some(master_key.id.clone()));
+            blob.wrapped_key = sea_orm::ActiveValue::Set(Some(master_key.wrap(&key, nonce)?));
+            blob.update(self.db()).await?;
+            rotated += 1;
+        }
+        info!(
+            "Wrapped the keys of {rotated} blobs with master key {}",
+            master_key.id
+        );
+        Ok(rotated)
+    }
+
+    /// Stream the stored object of `data` decrypted, or only `range` of it
+    ///
+    /// For compressed data, this is still compressed.
+    pub async fn stream_stored(
+        &self,
+        data: &StoredData,
+        range: Option<Range<u64>>,
+    ) -> Result<ByteStream, Error> {
+        let Some(key) = &data.data_key else {
+            return self.storage().stream(&data.key, range).await;
+        };
+        let encrypted_size = self.storage().stat(&data.key).await?.size;
+        let size = decrypted_size(encrypted_size);
+        let range = range.unwrap_or(0..size);
+        let range = range.start.min(size)..range.end.min(size);
+        if range.is_empty() {
+            return Ok(futures_util::stream::empty().boxed());
+        }
+
+        let first = range.start / SEGMENT_SIZE;
+        let last = (range.end - 1) / SEGMENT_SIZE;
+        let encrypted_range = first * ENCRYPTED_SEGMENT_SIZE
+            ..((last + 1) * ENCRYPTED_SEGMENT_SIZE).min(encrypted_size);
+        let encrypted = self
+            .storage()
+            .stream(&data.key, Some(encrypted_range))
+            .await?;
+        Ok(decrypt(
+            encrypted,
+            key.clone(),
+            first,
+            segment_count(encrypted_size),
+            (range.start - first * SEGMENT_SIZE) as usize,
+            range.end - range.start,
+        ))
+    }
+}
+
+/// Size of the data in an encrypted object of `encrypted_size` bytes
+pub fn decrypted_size(encrypted_size: u64) -> u64 {
+    encrypted_size.saturating_sub(segment_count(encrypted_size) * TAG_SIZE)
+}
+
+fn segment_count(encrypted_size: u64) -> u64 {
+    // even empty data has one segment
+    encrypted_size.div_ceil(ENCRYPTED_SEGMENT_SIZE).max(1)
+}
+
+fn segment_nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[..8].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_SIZE - 1] = last as u8;
+    nonce.into()
+}
+
+/// Encrypt everything from `data` with `key`
+pub fn encrypt(data: ByteStream, key: DataKey) -> ByteStream {
+    struct State {
+        data: ByteStream,
+        cipher: ChaCha20Poly1305,
+        buf: BytesMut,
+        index: u64,
+        done: bool,
+    }
+    let state = State {
+        data,
+        cipher: ChaCha20Poly1305::new(&key.0),
+        buf: BytesMut::new(),
+        index: 0,
+        done: false,
+    };
+    futures_util::stream::unfold(state, |mut state| async move {
+        if state.done {
+            return None;
+        }
+        // a full segment is only the last one if nothing comes after it
+        while state.buf.len() as u64 <= SEGMENT_SIZE {
+            match state.data.next().await {
+                Some(Ok(chunk)) => state.buf.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    state.done = true;
+                    return Some((Err(e), state));
+                }
+                None => break,
+            }
+        }
+        let last = state.buf.len() as u64 <= SEGMENT_SIZE;
+        let segment = state
+            .buf
+            .split_to(state.buf.len().min(SEGMENT_SIZE as usize));
+        let encrypted = state
+            .cipher
+            .encrypt(&segment_nonce(state.index, last), segment.as_ref())
+            .map(Bytes::from)
+            .map_err(|_| Error::Encryption);
+        state.index += 1;
+        state.done = last || encrypted.is_err();
+        Some((encrypted, state))
+    })
+    .boxed()
+}
+
+/// Decrypt the segments from `encrypted`, starting with the segment `first_index` of
+/// `segment_count`
+///
+/// Of the decrypted data, the first `skip` bytes are left out, and at most `take` bytes are
+/// returned.
+fn decrypt(
+    encrypted: ByteStream,
+    key: DataKey,
+    first_index: u64,
+    segment_count: u64,
+    skip: usize,
+    take: u64,
+) -> ByteStream {
+    struct State {
+        encrypted: ByteStream,
+        cipher: ChaCha20Poly1305,
+        buf: BytesMut,
+        index: u64,
+        skip: usize,
+        remaining: u64,
+    }
+    let state = State {
+        encrypted,
+        cipher: ChaCha20Poly1305::new(&key.0),
+        buf: BytesMut::new(),
+        index: first_index,
+        skip,
+        remaining: take,
+    };
+    futures_util::stream::unfold(state, move |mut state| async move {
+        if state.remaining == 0 {
+            return None;
+        }
+        let mut ended = false;
+        while (state.buf.len() as u64) < ENCRYPTED_SEGMENT_SIZE {
+            match state.encrypted.next().await {
+                Some(Ok(chunk)) => state.buf.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    state.remaining = 0;
+                    return Some((Err(e), state));
+                }
+                None => {
+                    ended = true;
+                    break;
+                }
+            }
+        }
+        let segment = state
+            .buf
+            .split_to(state.buf.len().min(ENCRYPTED_SEGMENT_SIZE as usize));
+        let last = state.index + 1 == segment_count;
+        if segment.is_empty() || (ended && !last) {
+            state.remaining = 0;
+            return Some((Err(Error::Decryption), state));
+        }
+        let decrypted = match state
+            .cipher
+            .decrypt(&segment_nonce(state.index, last), segment.as_ref())
+        {
+            Ok(decrypted) => decrypted,
+            Err(_) => {
+                state.remaining = 0;
+                return Some((Err(Error::Decryption), state));
+            }
+        };
+        state.index += 1;
+        let mut decrypted = Bytes::from(decrypted);
+        decrypted = decrypted.slice(state.skip.min(decrypted.len())..);
+        state.skip = 0;
+        decrypted.truncate(state.remaining.min(decrypted.len() as u64) as usize);
+        state.remaining -= decrypted.len() as u64;
+        if last {
+            state.remaining = 0;
+        }
+        Some((Ok(decrypted), state))
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::TryStreamExt;
+
+    use super::*;
+
+    fn key() -> DataKey {
+        DataKey(*Key::from_slice(&[7; 32]))
+    }
+
+    async fn roundtrip(data: &[u8], range: Range<u64>) -> Vec<u8> {
+        let chunks = data
+            .chunks(1000)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+        let encrypted: Vec<Bytes> = encrypt(futures_util::stream::iter(chunks).boxed(), key())
+            .try_collect()
+            .await
+            .unwrap();
+        let encrypted = encrypted.concat();
+        assert_eq!(decrypted_size(encrypted.len() as u64), data.len() as u64);
+
+        let first = range.start / SEGMENT_SIZE;
+        let stored = Bytes::from(encrypted).slice((first * ENCRYPTED_SEGMENT_SIZE) as usize..);
+        let stored = futures_util::stream::iter([Ok(stored)]).boxed();
+        let decrypted: Vec<Bytes> = decrypt(
+            stored,
+            key(),
+            first,
+            segment_count(encrypted_size(data.len() as u64)),
+            (range.start - first * SEGMENT_SIZE) as usize,
+            range.end - range.start,
+        )
+        .try_collect()
+        .await
+        .unwrap();
+        decrypted.concat()
+    }
+
+    fn encrypted_size(size: u64) -> u64 {
+        size + size.div_ceil(SEGMENT_SIZE).max(1) * TAG_SIZE
+    }
+
+    #[tokio::test]
+    async fn test_encryption_roundtrip() {
+        let data: Vec<u8> = (0..SEGMENT_SIZE * 3 + 17).map(|i| i as u8).collect();
+        for len in [0, 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, data.len() as u64] {
+            let data = &data[..len as usize];
+            assert_eq!(roundtrip(data, 0..len).await, data);
+        }
+        let range = SEGMENT_SIZE - 5..SEGMENT_SIZE * 2 + 5;
+        assert_eq!(
+            roundtrip(&data, range.clone()).await,
+            &data[range.start as usize..range.end as usize]
+        );
+    }
+
+    #[test]
+    fn test_wrap_data_key() {
+        let master_key = MasterKey::derive("hunter2");
+        let wrapped = master_key.wrap(&key(), [1; NONCE_SIZE]).unwrap();
+        assert_eq!(master_key.unwrap(&wrapped).unwrap().0, key().0);
+        assert!(MasterKey::derive("hunter3").unwrap(&wrapped).is_err());
+    }
+}
@@ -2,12 +2,14 @@ use actix_web::ResponseError;
 use actix_web::http::StatusCode;
 use actix_web::http::header::HeaderValue;
 use derive_builder::Builder;
-use log::{error, warn};
+use log::warn;
 use serde::Serialize;
 use std::num::ParseIntError;
 use std::string::FromUtf8Error;
 use thiserror::Error;
 
+use crate::files::{FID_LENGTHS, FileID};
+
 #[derive(Debug, Serialize, Builder, Default)]
 pub struct ErrorPageDetails {
     icon: String,
@@ -26,6 +28,20 @@ pub enum ConfigError {
     CouldNotReadFile(#[from] std::io::Error),
     #[error("Bad TOML in the config file: {0}")]
     ConfigSyntax(#[from] toml::de::Error),
+    #[error("The base_url is not an absolute url: {0}")]
+    BadBaseUrl(String),
+    #[error("The config section [{0}] is required but missing")]
+    MissingSection(String),
+    #[error("Encryption is enabled, but files.encryption.master_key is not set")]
+    MissingMasterKey,
+    #[error(
+        "files.id_length must be between {} and {}, not {length}",
+        FID_LENGTHS.start(),
+        FID_LENGTHS.end()
+    )]
+    BadIdLength { length: usize },
+    #[error("files.s3.endpoint is not an absolute url: {0}")]
+    BadS3Endpoint(String),
 }
 
 #[derive(Error, Debug)]
@@ -44,8 +60,10 @@ pub enum Error {
     FileNotFound,
     #[error("This file does not have a database entry")]
     FileDBEntryNotFound,
-    #[error("Could not parse FileID: {0}")]
-    BadFileID(#[from] ParseIntError),
+    #[error("Not a valid file id: {0}")]
+    BadFileID(String),
+    #[error("Could not parse the user id of the session: {0}")]
+    BadUserID(#[from] ParseIntError),
     #[error("Template Error: {0}")]
     Template(#[from] minijinja::Error),
     #[error("Could not parse variable from url encoding: {0}")]
@@ -88,6 +106,108 @@ pub enum Error {
     RegistrationClosed,
     #[error("The requested site does not exist")]
     SiteDoesNotExist,
+    #[error("Bad key for an object in the storage: {0}")]
+    BadStorageKey(String),
+    #[error("Object storage Error: {0}")]
+    ObjectStore(#[from] object_store::Error),
+    #[error("This upload would exceed your storage quota of {0}")]
+    UserQuotaExceeded(String),
+    #[error("There is not enough storage left on this server for this upload")]
+    StorageFull,
+    #[error("Could not parse duration: {0}")]
+    BadDuration(String),
+    #[error("The expiration must be between {0} and {1}")]
+    ExpirationOutOfRange(String, String),
+    #[error("The download limit must be a positive number: {0}")]
+    BadMaxDownloads(String),
+    #[error("This file is protected by a password")]
+    FilePasswordRequired,
+    #[error("Wrong password for this file")]
+    WrongFilePassword,
+    #[error("DB has no salt for the password of a file: {0}")]
+    NoSaltStoredForFile(String),
+    #[error("Could not read the session: {0}")]
+    SessionGet(#[from] actix_session::SessionGetError),
+    #[error("Could not write to the session: {0}")]
+    SessionInsert(#[from] actix_session::SessionInsertError),
+    #[error("Only version 1.0.0 of the tus protocol is supported")]
+    TusVersionMismatch,
+    #[error("The upload does not exist or has expired")]
+    UploadNotFound,
+    #[error("The upload is at offset {0}")]
+    UploadOffsetMismatch(u64),
+    #[error("The upload is being written by another request")]
+    UploadBusy,
+    #[error("Upload chunks must have the content type application/offset+octet-stream")]
+    BadUploadContentType,
+    #[error("The upload is larger than the allowed {0} bytes")]
+    UploadTooLarge(u64),
+    #[error("Error while receiving the upload: {0}")]
+    Payload(#[from] actix_web::error::PayloadError),
+    /// Not the [`MultipartError`](actix_multipart::MultipartError) itself, it can't be sent
+    /// between threads
+    #[error("Error while receiving the upload form: {0}")]
+    Multipart(String),
+    #[error("The upload form is missing the field '{0}'")]
+    MissingFormField(String),
+    #[error("Stored data is encrypted with an unknown master key: {0}")]
+    UnknownMasterKey(String),
+    #[error("Could not encrypt data for the storage")]
+    Encryption,
+    #[error("Stored data could not be decrypted, it was changed or the key is wrong")]
+    Decryption,
+    #[error("Unknown command: {0}")]
+    UnknownCommand(String),
+    #[error("The scrub found {0} problems that were not repaired")]
+    ScrubIssues(usize),
+    #[error("Stored data has an unknown compression: {0}")]
+    UnknownCompression(String),
+    #[error("Not a hex encoded SHA-256 checksum: {0}")]
+    BadChecksum(String),
+    #[error("The upload has the SHA-256 checksum {actual}, but {expected} was expected")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("Not a valid value for encrypted, use true or false: {0}")]
+    BadEncryptedFlag(String),
+    #[error("Not a valid slug, use 3 to 64 letters, digits and dashes: {0}")]
+    BadSlug(String),
+    #[error("This slug is reserved: {0}")]
+    ReservedSlug(String),
+    #[error("This slug is already taken: {0}")]
+    SlugTaken(String),
+    #[error("Only logged in users can choose a slug")]
+    SlugNeedsAccount,
+    #[error("Not a valid file name: {0}")]
+    BadFileName(String),
+    #[error("The description is longer than {0} characters")]
+    DescriptionTooLong(usize),
+    #[error("At most {0} files can be uploaded together")]
+    TooManyFiles(usize),
+    #[error("A slug can only be chosen for a single file")]
+    SlugForSeveralFiles,
+    #[error("Collection not found")]
+    CollectionNotFound,
+    #[error("This file is not an archive that can be browsed")]
+    NotAnArchive,
+    #[error("This entry does not exist in the archive")]
+    ArchiveEntryNotFound,
+    #[error("Could not read the archive: {0}")]
+    BadArchive(String),
+    #[error("The archive is too large to be browsed: {0}")]
+    ArchiveLimitExceeded(String),
+    #[error("File {0} has a download limit and can only be downloaded on its own")]
+    LimitedFileInArchive(FileID),
+    #[error("Could not write ZIP archive: {0}")]
+    Zip(#[from] async_zip::error::ZipError),
+    #[error("Could not make thumbnails: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("This file has no thumbnail")]
+    ThumbnailNotFound,
+}
+
+impl From<actix_multipart::MultipartError> for Error {
+    fn from(e: actix_multipart::MultipartError) -> Self {
+        Self::Multipart(e.to_string())
+    }
 }
 
 impl From<Error> for ErrorPageDetails {
@@ -124,16 +244,59 @@ impl From<Error> for ErrorPageDetails {
 impl actix_web::error::ResponseError for Error {
     fn status_code(&self) -> actix_web::http::StatusCode {
         match self {
-            Self::FileNotFound | Error::SiteDoesNotExist => actix_web::http::StatusCode::NOT_FOUND,
-            Self::Unauthorized | Self::WrongPassword | Self::RegistrationClosed => {
-                actix_web::http::StatusCode::UNAUTHORIZED
+            Self::FileNotFound
+            | Error::SiteDoesNotExist
+            | Self::UploadNotFound
+            | Self::CollectionNotFound
+            | Self::ArchiveEntryNotFound
+            | Self::ThumbnailNotFound => actix_web::http::StatusCode::NOT_FOUND,
+            Self::Unauthorized
+            | Self::WrongPassword
+            | Self::RegistrationClosed
+            | Self::FilePasswordRequired
+            | Self::WrongFilePassword
+            | Self::SlugNeedsAccount => actix_web::http::StatusCode::UNAUTHORIZED,
+            Self::TokenWithThatNameExists(_) | Self::SlugTaken(_) => {
+                actix_web::http::StatusCode::CONFLICT
             }
-            Self::TokenWithThatNameExists(_) => actix_web::http::StatusCode::CONFLICT,
-            Self::BadFileID(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            Self::UserQuotaExceeded(_) | Self::UploadTooLarge(_) => {
+                actix_web::http::StatusCode::PAYLOAD_TOO_LARGE
+            }
+            Self::TusVersionMismatch => actix_web::http::StatusCode::PRECONDITION_FAILED,
+            Self::UploadOffsetMismatch(_) | Self::UploadBusy => {
+                actix_web::http::StatusCode::CONFLICT
+            }
+            Self::BadUploadContentType => actix_web::http::StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::StorageFull => actix_web::http::StatusCode::INSUFFICIENT_STORAGE,
+            Self::BadFileID(_)
+            | Self::BadDuration(_)
+            | Self::ExpirationOutOfRange(_, _)
+            | Self::BadMaxDownloads(_)
+            | Self::MissingHeader(_)
+            | Self::BadHeader(_)
+            | Self::Payload(_)
+            | Self::Multipart(_)
+            | Self::MissingFormField(_)
+            | Self::BadChecksum(_)
+            | Self::ChecksumMismatch { .. }
+            | Self::BadEncryptedFlag(_)
+            | Self::BadSlug(_)
+            | Self::ReservedSlug(_)
+            | Self::BadFileName(_)
+            | Self::DescriptionTooLong(_)
+            | Self::TooManyFiles(_)
+            | Self::SlugForSeveralFiles
+            | Self::NotAnArchive
+            | Self::BadArchive(_)
+            | Self::ArchiveLimitExceeded(_)
+            | Self::LimitedFileInArchive(_) => actix_web::http::StatusCode::BAD_REQUEST,
             Self::IO(e) => match e.kind() {
                 std::io::ErrorKind::NotFound => actix_web::http::StatusCode::NOT_FOUND,
                 _ => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
             },
+            Self::ObjectStore(object_store::Error::NotFound { .. }) => {
+                actix_web::http::StatusCode::NOT_FOUND
+            }
             _ => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
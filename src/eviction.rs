@@ -0,0 +1,55 @@
+use log::{debug, info};
+use sea_orm::{EntityTrait as _, QueryOrder as _};
+
+use crate::config::EvictionPolicy;
+use crate::db::schema::file::{Column as FileC, Entity as FileE};
+use crate::errors::Error;
+use crate::state::AppState;
+
+impl AppState {
+    /// Delete files according to the `eviction_policy` until at least `bytes_to_free` bytes
+    /// are freed, or no files are left
+    ///
+    /// Returns how many bytes were actually freed.
+    pub async fn evict_files(&self, bytes_to_free: u64) -> Result<u64, Error> {
+        let policy = self.config().files.eviction_policy;
+        debug!("Evicting files to free {bytes_to_free} bytes with policy {policy:?}");
+        let candidates = match policy {
+            EvictionPolicy::ClosestToExpiration => {
+                FileE::find().order_by_asc(FileC::ExpirationTime)
+            }
+            // NOTE: files from before the creation time was tracked have none, they come first
+            EvictionPolicy::Oldest => FileE::find().order_by_asc(FileC::CreationTime),
+        }
+        .all(self.db())
+        .await?;
+
+        let mut freed: u64 = 0;
+        for file in candidates {
+            if freed >= bytes_to_free {
+                break;
+            }
+            info!(
+                "Evicting file {} ({} bytes, uploaded {:?}, expires {}) to free storage",
+                file.id, file.size, file.creation_time, file.expiration_time
+            );
+            // files that share their blob with others free nothing
+            freed += self.remove_file(file).await?;
+        }
+
+        info!("Evicted files with a total of {freed} bytes");
+        Ok(freed)
+    }
+
+    /// Evict files if the instance uses more than `max_storage`, and `delete_old_files` is set
+    pub async fn evict_files_if_over_quota(&self) -> Result<(), Error> {
+        if !self.config().files.delete_old_files {
+            return Ok(());
+        }
+        let usage = self.storage_usage_total().await?;
+        if usage.used > usage.quota {
+            self.evict_files(usage.used - usage.quota).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//! Changing files after they were uploaded, for `PATCH /api/v1/file/{fid}`
+
+use log::info;
+use sea_orm::ActiveModelTrait as _;
+use serde::Deserialize;
+
+use crate::config::HumanDuration;
+use crate::db::schema::file;
+use crate::errors::Error;
+use crate::files::FileID;
+use crate::state::AppState;
+use crate::storage;
+use crate::user::User;
+
+/// Longest description of a file, in characters
+pub const MAX_DESCRIPTION_LENGTH: usize = 2000;
+/// Longest name of a file, in bytes
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// What to change about a file, everything that is `None` stays as it is
+#[derive(Debug, Default, Deserialize)]
+pub struct FileEdit {
+    /// Counted from now, within the expiration limits of the owner
+    pub expires_in: Option<HumanDuration>,
+    /// Links with the old name stop working
+    pub name: Option<String>,
+    /// An empty description removes it
+    pub description: Option<String>,
+}
+
+/// Check that `raw` can be the name of a file, returns it trimmed
+pub fn parse_name(raw: &str) -> Result<String, Error> {
+    let name = raw.trim();
+    if name.is_empty()
+        || name.len() > MAX_NAME_LENGTH
+        || name == "."
+        || name == ".."
+        || name
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c.is_control())
+    {
+        return Err(Error::BadFileName(raw.to_string()));
+    }
+    Ok(name.to_string())
+}
+
+impl AppState {
+    /// Apply `edit` to the file `fid` of `user`
+    ///
+    /// A new expiration of a file in a collection applies to the whole collection. Nothing is
+    /// changed if any part of `edit` is invalid. Returns the name of the file, which
+    /// may be new.
+    pub async fn edit_file(
+        &self,
+        user: &User,
+        fid: FileID,
+        edit: &FileEdit,
+    ) -> Result<String, Error> {
+        let file = self.get_owned_file(user, fid).await?;
+        let expiration = edit
+            .expires_in
+            .map(|expires_in| self.expiration_time(Some(user), Some(expires_in)))
+            .transpose()?;
+        let description = match edit.description.as_deref().map(str::trim) {
+            Some(d) if d.chars().count() > MAX_DESCRIPTION_LENGTH => {
+                return Err(Error::DescriptionTooLong(MAX_DESCRIPTION_LENGTH));
+            }
+            Some("") => Some(None),
+            Some(d) => Some(Some(d.to_string())),
+            None => None,
+        };
+        let old_name = match &file.name {
+            Some(name) => name.clone(),
+            None => self.get_filename_for_fid(fid).await?,
+        };
+        let name = edit.name.as_deref().map(parse_name).transpose()?;
+        // the data of old files is stored under their name, newer ones are in blobs
+        let moved = match &name {
+            Some(name) if *name != old_name && file.blob.is_none() => {
+                let (from, to) = (
+                    storage::key_fid_datafile(fid, &old_name),
+                    storage::key_fid_datafile(fid, name),
+                );
+                self.storage().rename(&from, &to).await?;
+                Some((to, from))
+            }
+            _ => None,
+        };
+
+        let collection = file.collection_id.clone();
+        let mut active: file::ActiveModel = file.into();
+        if let Some(expiration) = expiration {
+            info!("Changing the expiration of {fid} to {expiration}");
+            active.expiration_time = sea_orm::ActiveValue::Set(expiration);
+        }
+        if let Some(description) = description {
+            active.description = sea_orm::ActiveValue::Set(description);
+        }
+        if let Some(name) = &name {
+            info!("Renaming {fid} from {old_name} to {name}");
+            active.name = sea_orm::ActiveValue::Set(Some(name.clone()));
+        }
+        if !active.is_changed() {
+            return Ok(old_name);
+        }
+        if let Err(e) = active.update(self.db()).await {
+            if let Some((to, from)) = moved {
+                self.storage().rename(&to, &from).await?;
+            }
+            return Err(e.into());
+        }
+        // the files of a collection expire together
+        if let Some(expiration) = expiration
+            && let Some(collection) = collection
+        {
+            self.set_collection_expiration(&collection, expiration)
+                .await?;
+        }
+        Ok(name.unwrap_or(old_name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_name;
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(parse_name(" report.pdf ").unwrap(), "report.pdf");
+        assert_eq!(
+            parse_name("Q3 roadmap (final).odt").unwrap(),
+            "Q3 roadmap (final).odt"
+        );
+        assert!(parse_name("").is_err());
+        assert!(parse_name("..").is_err());
+        assert!(parse_name("../etc/passwd").is_err());
+        assert!(parse_name("a\\b").is_err());
+        assert!(parse_name("line\nbreak").is_err());
+        assert!(parse_name(&"x".repeat(256)).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+//! Listing the files of a user, for `GET /api/v1/files` and the "My files" page
+//!
+//! Filtering, sorting and splitting into pages is done by the DB, so that only the files of one
+//! page are loaded. The content type of a file is detected from its data when it is uploaded
+//! and kept in the DB for this, the same as [`FileInfos`](crate::files::FileInfos) reports.
+
+use chrono::NaiveDateTime;
+use sea_orm::sea_query::{Expr, Func, LikeExpr, SimpleExpr};
+use sea_orm::{
+    ColumnTrait as _, Condition, EntityTrait as _, Order, PaginatorTrait as _, QueryFilter as _,
+    QueryOrder as _,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::HumanDuration;
+use crate::db::schema::file::{Column as FileC, Entity as FileE, Model as FileM};
+use crate::errors::Error;
+use crate::files::FileID;
+use crate::state::AppState;
+use crate::user::User;
+
+/// Most files on one page of a [`FileList`]
+pub const MAX_PER_PAGE: u64 = 100;
+
+/// By what a [`FileList`] is sorted
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileSort {
+    #[default]
+    Created,
+    Expiration,
+    Name,
+    Size,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Which files go into a [`FileList`], from the query of the request
+#[derive(Debug, Deserialize)]
+pub struct FileListQuery {
+    /// Starts at 1
+    #[serde(default = "default_page")]
+    pub page: u64,
+    /// At most [`MAX_PER_PAGE`]
+    #[serde(default = "default_per_page")]
+    pub per_page: u64,
+    #[serde(default)]
+    pub sort: FileSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Only files whose name contains this, ignoring case
+    pub name: Option<String>,
+    /// Only files whose content type starts with this, like `image/`
+    pub content_type: Option<String>,
+    /// Only files that expire within this duration, like `1d`
+    pub expires_within: Option<HumanDuration>,
+}
+
+/// A file in a [`FileList`]
+///
+/// Unlike [`FileInfos`](crate::files::FileInfos), this only has what is known without reading
+/// the data of the file, the content type is the one detected on upload.
+#[derive(Debug, Serialize)]
+pub struct FileSummary {
+    pub fid: FileID,
+    pub name: String,
+    pub slug: Option<String>,
+    pub description: Option<String>,
+    pub url_raw: String,
+    pub url_frontend: String,
+    pub url_slug: Option<String>,
+    pub size: u64,
+    /// human readable size
+    pub size_human: String,
+    pub content_type: String,
+    /// `None` for old files
+    pub time_created: Option<NaiveDateTime>,
+    pub time_expiration: NaiveDateTime,
+    /// `None` if the file can be downloaded any number of times
+    pub downloads_remaining: Option<u64>,
+    pub password_protected: bool,
+    pub encrypted: bool,
+}
+
+/// One page of the files of a user
+#[derive(Debug, Serialize)]
+pub struct FileList {
+    pub files: Vec<FileSummary>,
+    pub page: u64,
+    pub per_page: u64,
+    /// How many files match the query on all pages
+    pub total: u64,
+}
+
+impl Default for FileListQuery {
+    fn default() -> Self {
+        Self {
+            page: default_page(),
+            per_page: default_per_page(),
+            sort: FileSort::default(),
+            order: SortOrder::default(),
+            name: None,
+            content_type: None,
+            expires_within: None,
+        }
+    }
+}
+
+impl FileListQuery {
+    /// Which files match the filters of this query
+    fn condition(&self, now: NaiveDateTime) -> Condition {
+        let mut condition = Condition::all();
+        if let Some(name) = &self.name {
+            // the DB ignores the case of ASCII letters
+            condition =
+                condition.add(Expr::col(FileC::Name).like(escape_like(name.trim(), "%", "%")));
+        }
+        if let Some(content_type) = &self.content_type {
+            condition = condition.add(Expr::col(FileC::ContentType).like(escape_like(
+                content_type.trim(),
+                "",
+                "%",
+            )));
+        }
+        if let Some(within) = self.expires_within {
+            condition = condition.add(FileC::ExpirationTime.lte(now + within.0));
+        }
+        condition
+    }
+
+    /// What the files are sorted by
+    fn sort_expr(&self) -> SimpleExpr {
+        match self.sort {
+            FileSort::Created => Expr::col(FileC::CreationTime).into(),
+            FileSort::Expiration => Expr::col(FileC::ExpirationTime).into(),
+            FileSort::Name => Func::lower(Expr::col(FileC::Name)).into(),
+            FileSort::Size => Expr::col(FileC::Size).into(),
+        }
+    }
+}
+
+/// A `LIKE` pattern of `prefix`, `text` matched literally and `suffix`
+fn escape_like(text: &str, prefix: &str, suffix: &str) -> LikeExpr {
+    let text = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    LikeExpr::new(format!("{prefix}{text}{suffix}")).escape('\\')
+}
+
+impl AppState {
+    /// The files of `user` that match `query`, on the page of `query`
+    pub async fn list_files(&self, user: &User, query: &FileListQuery) -> Result<FileList, Error> {
+        let now = chrono::Utc::now().naive_utc();
+        let order = match query.order {
+            SortOrder::Asc => Order::Asc,
+            SortOrder::Desc => Order::Desc,
+        };
+        let page = query.page.max(1);
+        let per_page = query.per_page.clamp(1, MAX_PER_PAGE);
+        let paginator = FileE::find()
+            .filter(FileC::UserId.eq(user.id()))
+            .filter(query.condition(now))
+            .order_by(query.sort_expr(), order.clone())
+            // files that sort the same stay on their page
+            .order_by(FileC::Id, order)
+            .paginate(self.db(), per_page);
+        let total = paginator.num_items().await?;
+
+        let mut files = Vec::new();
+        for file in paginator.fetch_page(page - 1).await? {
+            files.push(self.file_summary(file).await?);
+        }
+        Ok(FileList {
+            files,
+            page,
+            per_page,
+            total,
+        })
+    }
+
+    pub(crate) async fn file_summary(&self, file: FileM) -> Result<FileSummary, Error> {
+        let fid: FileID = file.id.into();
+        let name = match file.name {
+            Some(name) => name,
+            None => self.get_filename_for_fid(fid).await?,
+        };
+        // only files whose data can't be read have no content type
+        let content_type = file
+            .content_type
+            .unwrap_or_else(|| mime::APPLICATION_OCTET_STREAM.to_string());
+        Ok(FileSummary {
+            fid,
+            url_raw: self.uri_api_file_fid_name(fid, &name).to_string(),
+            url_frontend: self.uri_frontend_file_fid_name(fid, &name).to_string(),
+            url_slug: file
+                .slug
+                .as_deref()
+                .map(|slug| self.uri_frontend_file_slug(slug).to_string()),
+            slug: file.slug,
+            description: file.description,
+            size: file.size as u64,
+            size_human: human_bytes::human_bytes(file.size as f64),
+            content_type,
+            time_created: file.creation_time,
+            time_expiration: file.expiration_time,
+            downloads_remaining: file
+                .max_downloads
+                .map(|max| max.saturating_sub(file.download_count).max(0) as u64),
+            password_protected: file.password_hash.is_some(),
+            encrypted: file.encrypted,
+            name,
+        })
+    }
+}
+
+fn default_page() -> u64 {
+    1
+}
+
+fn default_per_page() -> u64 {
+    25
+}
+
+#[cfg(test)]
+mod test {
+    use migrations::MigratorTrait as _;
+    use sea_orm::{ActiveValue, Database, QuerySelect as _};
+
+    use super::*;
+    use crate::db::schema::file;
+
+    #[tokio::test]
+    async fn test_file_list_filter() {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        migrations::Migrator::up(&db, None).await.unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        for (id, name, content_type, hours) in [
+            (1, "Team-Roadmap.pdf", "application/pdf; charset=binary", 2),
+            (2, "roadmap.png", "image/png; charset=binary", 48),
+            (3, "100%_done.txt", "text/plain; charset=us-ascii", 48),
+            (4, "100 done.txt", "text/plain; charset=us-ascii", 1),
+        ] {
+            FileE::insert(file::ActiveModel {
+                id: ActiveValue::Set(id),
+                expiration_time: ActiveValue::Set(now + chrono::TimeDelta::hours(hours)),
+                size: ActiveValue::Set(10 * id),
+                download_count: ActiveValue::Set(0),
+                encrypted: ActiveValue::Set(false),
+                name: ActiveValue::Set(Some(name.to_string())),
+                content_type: ActiveValue::Set(Some(content_type.to_string())),
+                ..Default::default()
+            })
+            .exec(&db)
+            .await
+            .unwrap();
+        }
+        let ids = async |query: &FileListQuery| -> Vec<i64> {
+            FileE::find()
+                .select_only()
+                .column(FileC::Id)
+                .filter(query.condition(now))
+                .order_by(query.sort_expr(), Order::Asc)
+                .into_tuple()
+                .all(&db)
+                .await
+                .unwrap()
+        };
+
+        let mut query = FileListQuery {
+            name: Some("roadmap".to_string()),
+            sort: FileSort::Name,
+            ..Default::default()
+        };
+        assert_eq!(ids(&query).await, [2, 1]);
+        query.content_type = Some("application/".to_string());
+        assert_eq!(ids(&query).await, [1]);
+        query.expires_within = Some("1h".parse().unwrap());
+        assert!(ids(&query).await.is_empty());
+        query.expires_within = Some("1d".parse().unwrap());
+        assert_eq!(ids(&query).await, [1]);
+        query.content_type = Some("image/".to_string());
+        assert!(ids(&query).await.is_empty());
+
+        // wildcards in the query are matched literally
+        let query = FileListQuery {
+            name: Some("100%_".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(&query).await, [3]);
+    }
+}
@@ -1,27 +1,90 @@
 use std::fmt::Display;
-use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
-use std::time::SystemTime;
 
-use actix_multipart::form::{MultipartForm, tempfile::TempFile};
+use actix_web::HttpRequest;
 use actix_web::http::header::ContentType;
-use chrono::{NaiveDateTime, Utc};
+use chrono::NaiveDateTime;
 use derive_builder::Builder;
 use log::{debug, warn};
-use rand::distr::StandardUniform;
 use rand::prelude::*;
 use sea_orm::EntityTrait;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::db::types::RawFileID;
 use crate::errors::Error;
+use crate::storage::ObjectMeta;
 use crate::user::User;
 
-#[derive(Debug, MultipartForm)]
-pub struct FileUpload {
-    #[multipart(limit = "10GB")]
-    pub file: TempFile,
+/// Form field of an upload that holds the file data
+pub const UPLOAD_FILE_FIELD: &str = "file";
+/// Used if an upload does not come with a file name
+pub const DEFAULT_UPLOAD_NAME: &str = "upload";
+
+/// Alternative to the `expires_in` field of an upload
+pub const EXPIRES_IN_HEADER: &str = "Expires-In";
+/// Alternative to the `max_downloads` field of an upload
+pub const MAX_DOWNLOADS_HEADER: &str = "Max-Downloads";
+/// Alternative to the `password` field of an upload, also used to download protected files
+pub const FILE_PASSWORD_HEADER: &str = "File-Password";
+/// Alternative to the `sha256` field of an upload
+pub const FILE_SHA256_HEADER: &str = "File-Sha256";
+/// Alternative to the `encrypted` field of an upload
+pub const FILE_ENCRYPTED_HEADER: &str = "File-Encrypted";
+/// Alternative to the `slug` field of an upload
+pub const FILE_SLUG_HEADER: &str = "File-Slug";
+
+/// Lengths that `files.id_length` may have
+pub const FID_LENGTHS: RangeInclusive<usize> = 4..=10;
+/// Digits of the short form of a [`FileID`]
+const FID_ALPHABET: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+/// Raw ids below this are from before the short form and are written in decimal
+const LEGACY_FID_LIMIT: RawFileID = 1 << 32;
+
+/// Optional settings of an upload, unparsed
+///
+/// These come from form fields of the same name, or from the equivalent headers.
+#[derive(Debug, Default)]
+pub struct UploadOptions {
+    /// How long until the file expires, like `7d`
+    pub expires_in: Option<String>,
+    /// Delete the file after this many downloads
+    pub max_downloads: Option<String>,
+    /// Require this password to download the file
+    pub password: Option<String>,
+    /// Reject the upload if its data does not have this hex encoded SHA-256 checksum
+    pub sha256: Option<String>,
+    /// `true` if the data was encrypted by the uploader, the server only stores it
+    pub encrypted: Option<String>,
+    /// A custom name for the links of the file, only for registered users
+    pub slug: Option<String>,
+}
+
+/// Everything that goes into the DB entry of a new file
+#[derive(Debug, Clone)]
+pub struct NewFile {
+    pub size: u64,
+    pub expiration: NaiveDateTime,
+    pub max_downloads: Option<u32>,
+    pub password_hash: Option<String>,
+    /// Normalized checksum from [`UploadOptions::sha256`]
+    pub expected_sha256: Option<String>,
+    /// Set with [`NewFile::set_hashes`] once all data was received
+    pub hashes: Option<FileHashes>,
+    /// From [`UploadOptions::encrypted`]
+    pub encrypted: bool,
+    /// Normalized slug from [`UploadOptions::slug`]
+    pub slug: Option<String>,
+    /// Id of the collection that the file is uploaded with
+    pub collection: Option<String>,
+}
+
+/// Hex encoded checksums of the data of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHashes {
+    pub sha256: String,
+    /// Only computed if `files.blake3` is enabled
+    pub blake3: Option<String>,
 }
 
 #[derive(Debug, Serialize, Builder)]
@@ -41,6 +104,34 @@ pub struct FileInfos {
     pub time_modified: NaiveDateTime,
     pub time_accessed: NaiveDateTime,
     pub time_expiration: NaiveDateTime,
+    /// `None` if the file can be downloaded any number of times
+    pub downloads_remaining: Option<u64>,
+    pub password_protected: bool,
+    /// hex encoded, `None` for old files that were not hashed yet
+    pub sha256: Option<String>,
+    pub blake3: Option<String>,
+    /// The data was encrypted by the uploader, the key is not known to the server
+    pub encrypted: bool,
+    pub slug: Option<String>,
+    pub description: Option<String>,
+    /// Id of the collection that the file was uploaded with
+    pub collection: Option<String>,
+    /// Frontend url of the collection, set by
+    /// [`AppState::make_file_infos`](crate::state::AppState::make_file_infos)
+    #[builder(default)]
+    pub url_collection: Option<String>,
+    /// Frontend url with the slug instead of the id, set by
+    /// [`AppState::make_file_infos`](crate::state::AppState::make_file_infos)
+    #[builder(default)]
+    pub url_slug: Option<String>,
+    /// Small version of an image, set by
+    /// [`AppState::make_file_infos`](crate::state::AppState::make_file_infos)
+    #[builder(default)]
+    pub url_thumbnail: Option<String>,
+    /// Medium sized version of an image for its preview, set by
+    /// [`AppState::make_file_infos`](crate::state::AppState::make_file_infos)
+    #[builder(default)]
+    pub url_preview: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -49,8 +140,12 @@ pub struct SerializeableContentType {
     inner: String,
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
-#[serde(transparent)]
+/// Identifies a file in urls and the storage
+///
+/// New ids are written in base62 with `files.id_length` digits, for example `4fTq9Zb1`. Ids of
+/// files from before that are written in decimal, which is only used for them, so that their
+/// links keep working. The storage keys always use the [raw id](FileID::inner).
+#[derive(Debug, Copy, Clone)]
 pub struct FileID {
     inner: RawFileID,
 }
@@ -59,6 +154,73 @@ impl FileID {
     pub fn inner(&self) -> RawFileID {
         self.inner
     }
+
+    /// Make a random id that is written with `length` base62 digits
+    ///
+    /// Ids that would only be made of decimal digits are skipped, they would be taken for old ids.
+    pub fn random<R: Rng + ?Sized>(rng: &mut R, length: usize) -> Self {
+        let length = length.clamp(*FID_LENGTHS.start(), *FID_LENGTHS.end()) as u32;
+        let lowest = 62i64.pow(length - 1);
+        loop {
+            let fid = FileID {
+                inner: LEGACY_FID_LIMIT + rng.random_range(lowest..lowest * 62),
+            };
+            if !fid.to_string().bytes().all(|c| c.is_ascii_digit()) {
+                return fid;
+            }
+        }
+    }
+}
+
+impl UploadOptions {
+    /// Take the options from the headers of `req`
+    pub fn from_headers(req: &HttpRequest) -> Result<Self, Error> {
+        Ok(Self {
+            expires_in: header_value(req, EXPIRES_IN_HEADER)?,
+            max_downloads: header_value(req, MAX_DOWNLOADS_HEADER)?,
+            password: header_value(req, FILE_PASSWORD_HEADER)?,
+            sha256: header_value(req, FILE_SHA256_HEADER)?,
+            encrypted: header_value(req, FILE_ENCRYPTED_HEADER)?,
+            slug: header_value(req, FILE_SLUG_HEADER)?,
+        })
+    }
+
+    /// Set the option belonging to the form field `name`, returns `false` if there is none
+    ///
+    /// Empty values are ignored, so that empty form fields don't override headers.
+    pub fn set_field(&mut self, name: &str, value: &str) -> bool {
+        let option = match name {
+            "expires_in" => &mut self.expires_in,
+            "max_downloads" => &mut self.max_downloads,
+            "password" => &mut self.password,
+            "sha256" => &mut self.sha256,
+            "encrypted" => &mut self.encrypted,
+            "slug" => &mut self.slug,
+            _ => return false,
+        };
+        if !value.trim().is_empty() {
+            *option = Some(value.trim().to_string());
+        }
+        true
+    }
+}
+
+impl NewFile {
+    /// Remember the checksums of the received data
+    ///
+    /// Fails with [`Error::ChecksumMismatch`] if the uploader expected another checksum.
+    pub fn set_hashes(&mut self, hashes: FileHashes) -> Result<(), Error> {
+        if let Some(expected) = &self.expected_sha256
+            && *expected != hashes.sha256
+        {
+            return Err(Error::ChecksumMismatch {
+                expected: expected.clone(),
+                actual: hashes.sha256,
+            });
+        }
+        self.hashes = Some(hashes);
+        Ok(())
+    }
 }
 
 impl FileInfos {
@@ -72,16 +234,12 @@ impl FileInfos {
 }
 
 impl FileInfosBuilder {
-    pub fn filemeta(&mut self, path: &Path) -> Result<&mut Self, Error> {
-        let fsmeta = std::fs::metadata(path)?;
-
-        self.size(fsmeta.size())
-            .time_created(systime_to_chrono(fsmeta.created()?))
-            .time_modified(systime_to_chrono(fsmeta.modified()?))
-            .time_accessed(systime_to_chrono(fsmeta.accessed()?))
-            .size_human(human_bytes::human_bytes(fsmeta.size() as u32));
-
-        Ok(self)
+    pub fn objectmeta(&mut self, meta: &ObjectMeta) -> &mut Self {
+        self.size(meta.size)
+            .time_created(meta.time_created)
+            .time_modified(meta.time_modified)
+            .time_accessed(meta.time_accessed)
+            .size_human(human_bytes::human_bytes(meta.size as u32))
     }
 
     pub async fn get_db_info(
@@ -112,7 +270,23 @@ impl FileInfosBuilder {
         debug!("User for fent: {user:?}");
 
         self.uploader(user);
+        // the storage only knows when the blob was created, which may be shared
+        if let Some(time_created) = file_meta.creation_time {
+            self.time_created(time_created);
+        }
         self.time_expiration(file_meta.expiration_time);
+        self.downloads_remaining(
+            file_meta
+                .max_downloads
+                .map(|max| max.saturating_sub(file_meta.download_count).max(0) as u64),
+        );
+        self.password_protected(file_meta.password_hash.is_some());
+        self.sha256(file_meta.sha256);
+        self.blake3(file_meta.blake3);
+        self.encrypted(file_meta.encrypted);
+        self.slug(file_meta.slug);
+        self.description(file_meta.description);
+        self.collection(file_meta.collection_id);
 
         Ok(self)
     }
@@ -120,15 +294,20 @@ impl FileInfosBuilder {
 
 impl Display for FileID {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.inner)
-    }
-}
-
-impl Distribution<FileID> for StandardUniform {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> FileID {
-        FileID {
-            inner: rng.random(),
+        if self.inner < LEGACY_FID_LIMIT {
+            return write!(f, "{}", self.inner);
+        }
+        let mut rest = self.inner - LEGACY_FID_LIMIT;
+        let mut digits = Vec::new();
+        loop {
+            digits.push(FID_ALPHABET[(rest % 62) as usize]);
+            rest /= 62;
+            if rest == 0 {
+                break;
+            }
         }
+        digits.reverse();
+        write!(f, "{}", String::from_utf8_lossy(&digits))
     }
 }
 
@@ -136,7 +315,41 @@ impl FromStr for FileID {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(FileID { inner: s.parse()? })
+        let bad = || Error::BadFileID(s.to_string());
+        if s.is_empty() || s.len() > *FID_LENGTHS.end() {
+            return Err(bad());
+        }
+        if s.bytes().all(|c| c.is_ascii_digit()) {
+            let legacy: u32 = s.parse().map_err(|_| bad())?;
+            return Ok(FileID {
+                inner: legacy.into(),
+            });
+        }
+        let mut value: RawFileID = 0;
+        for c in s.bytes() {
+            let digit = FID_ALPHABET.iter().position(|d| *d == c).ok_or_else(bad)?;
+            value = value
+                .checked_mul(62)
+                .and_then(|v| v.checked_add(digit as RawFileID))
+                .ok_or_else(bad)?;
+        }
+        Ok(FileID {
+            inner: LEGACY_FID_LIMIT + value,
+        })
+    }
+}
+
+impl Serialize for FileID {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for FileID {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        String::deserialize(d)?
+            .parse()
+            .map_err(serde::de::Error::custom)
     }
 }
 
@@ -180,7 +393,20 @@ impl From<RawFileID> for FileID {
     }
 }
 
-fn ser_uploader<S: Serializer>(user: &Option<User>, s: S) -> Result<S::Ok, S::Error> {
+fn header_value(req: &HttpRequest, header: &str) -> Result<Option<String>, Error> {
+    match req.headers().get(header) {
+        Some(value) => Ok(Some(
+            value
+                .to_str()
+                .map_err(|e| Error::BadHeader(e.to_string()))?
+                .trim()
+                .to_string(),
+        )),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn ser_uploader<S: Serializer>(user: &Option<User>, s: S) -> Result<S::Ok, S::Error> {
     debug!("serializing user for file entry: {user:?}");
     match user {
         None => "Anonymous".serialize(s),
@@ -188,21 +414,60 @@ fn ser_uploader<S: Serializer>(user: &Option<User>, s: S) -> Result<S::Ok, S::Er
     }
 }
 
-fn systime_to_chrono(t: SystemTime) -> chrono::NaiveDateTime {
-    let datetime: chrono::DateTime<Utc> = chrono::DateTime::from(t);
-    datetime.naive_utc()
-}
-
 #[cfg(test)]
 mod test {
-    use super::FileID;
+    use std::str::FromStr;
+
+    use super::{FileHashes, FileID, NewFile};
 
     #[test]
     fn test_fid() {
         let mut fid: FileID;
-        for _ in 0..1000 {
-            fid = rand::random();
-            assert!(!fid.to_string().contains(" "))
+        let mut rng = rand::rng();
+        for length in [4, 8, 10] {
+            for _ in 0..1000 {
+                fid = FileID::random(&mut rng, length);
+                let s = fid.to_string();
+                assert!(!s.contains(" "));
+                assert_eq!(s.len(), length);
+                assert_eq!(FileID::from_str(&s).unwrap().inner(), fid.inner());
+            }
         }
     }
+
+    #[test]
+    fn test_fid_legacy() {
+        let fid = FileID::from_str("3735928559").unwrap();
+        assert_eq!(fid.inner(), 3735928559);
+        assert_eq!(fid.to_string(), "3735928559");
+        assert_eq!(FileID::from(0).to_string(), "0");
+        assert!(FileID::from_str("4294967296").is_err());
+        assert!(FileID::from_str("").is_err());
+        assert!(FileID::from_str("abc-def").is_err());
+        assert!(FileID::from_str("zzzzzzzzzzz").is_err());
+    }
+
+    #[test]
+    fn test_expected_sha256() {
+        let hashes = FileHashes {
+            sha256: "ab".repeat(32),
+            blake3: None,
+        };
+        let mut new_file = NewFile {
+            size: 0,
+            expiration: chrono::NaiveDateTime::default(),
+            max_downloads: None,
+            password_hash: None,
+            expected_sha256: Some("ab".repeat(32)),
+            hashes: None,
+            encrypted: false,
+            slug: None,
+            collection: None,
+        };
+        assert!(new_file.set_hashes(hashes.clone()).is_ok());
+        assert_eq!(new_file.hashes, Some(hashes.clone()));
+
+        new_file.expected_sha256 = Some("cd".repeat(32));
+        assert!(new_file.set_hashes(hashes).is_err());
+    }
 }
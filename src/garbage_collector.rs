@@ -1,6 +1,5 @@
 use actix_web::web::Data;
 use log::{debug, error, info};
-use sea_orm::ModelTrait;
 
 use crate::{errors::Error, state::AppState as InnerAppState};
 
@@ -10,6 +9,12 @@ pub async fn garbage_collector(state: AppState) {
     loop {
         info!("Running garbage collector workload");
         run_with_guard(async || clear_expired_files(state.clone()).await).await;
+        run_with_guard(async || state.clear_expired_collections().await).await;
+        run_with_guard(async || state.clear_expired_partial_uploads().await).await;
+        run_with_guard(async || state.evict_files_if_over_quota().await).await;
+        if state.config().files.scrub.enabled {
+            run_with_guard(async || state.scrub(state.config().files.scrub.repair).await).await;
+        }
 
         info!("Workload finished, sleeping until next interval");
         tokio::time::sleep(state.garbage_collection_duration()).await;
@@ -21,9 +26,7 @@ async fn clear_expired_files(state: AppState) -> Result<(), Error> {
     for file in state.files().await? {
         if file.expiration_time < now {
             info!("File has expired: {}", file.id);
-            let file_dir = state.upload_dir_for_fid(file.id, false)?;
-            std::fs::remove_dir_all(file_dir)?;
-            file.delete(state.db()).await?;
+            state.remove_file(file).await?;
         } else {
             #[cfg(debug_assertions)]
             debug!("File is not expired but was checked: {}", file.id)
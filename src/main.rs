@@ -1,7 +1,6 @@
 use std::path::PathBuf;
 
 use actix_identity::IdentityMiddleware;
-use actix_multipart::form::MultipartFormConfig;
 use actix_session::SessionMiddleware;
 use actix_session::config::PersistentSession;
 use actix_session::storage::CookieSessionStore;
@@ -9,19 +8,35 @@ use actix_web::cookie::Key;
 use actix_web::cookie::time::Duration;
 use actix_web::http::KeepAlive;
 use actix_web::middleware::Logger;
-use actix_web::web::{FormConfig, PayloadConfig};
 use actix_web::{App, HttpServer, web};
 use actix_web_static_files::ResourceFiles;
 use log::trace;
 
 mod api_v1;
+mod archive;
+mod archive_contents;
 mod auth;
+mod blobs;
+mod collections;
+mod compression;
 mod config;
 mod db;
+mod encryption;
 mod errors;
+mod eviction;
+mod file_edit;
+mod file_list;
 mod files;
 mod garbage_collector;
+mod protection;
+mod quota;
+mod scrub;
+mod slugs;
 mod state;
+mod storage;
+mod thumbnails;
+mod tus;
+mod upload;
 mod urls;
 mod user;
 mod views;
@@ -32,6 +47,8 @@ use self::errors::Error;
 use self::garbage_collector::garbage_collector;
 use self::state::AppState;
 use self::state::load_config;
+use self::thumbnails::thumbnail_worker;
+use self::tus::*;
 use self::views::*;
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
@@ -43,11 +60,16 @@ async fn main() -> Result<(), Error> {
     let config = load_config("./data/config.toml")?;
 
     let inner_state = AppState::new(&config).await?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return run_command(&inner_state, &args).await;
+    }
     let app_state = web::Data::new(inner_state);
     let app_state_gc = app_state.clone();
-    let largest_possible_upload = config.largest_possible_upload();
+    let app_state_thumbnails = app_state.clone();
 
     tokio::spawn(async move { garbage_collector(app_state_gc).await });
+    tokio::spawn(async move { thumbnail_worker(app_state_thumbnails).await });
 
     HttpServer::new(move || {
         let generated_static_files = generate();
@@ -56,9 +78,6 @@ async fn main() -> Result<(), Error> {
         App::new()
             .configure(actix_config_global)
             .app_data(app_state.clone())
-            .app_data(PayloadConfig::new(largest_possible_upload))
-            .app_data(FormConfig::default().limit(largest_possible_upload))
-            .app_data(MultipartFormConfig::default().total_limit(largest_possible_upload))
             .wrap(Logger::default())
             .wrap(IdentityMiddleware::default())
             .wrap(
@@ -81,14 +100,32 @@ async fn main() -> Result<(), Error> {
             .service(frontend_view_get_logout)
             .service(frontend_view_post_register)
             .service(frontend_view_get_settings)
+            .service(frontend_view_get_files)
+            .service(frontend_view_get_collection_id)
             .service(frontend_view_get_about)
+            .service(frontend_view_get_unlock_fid)
+            .service(frontend_view_post_unlock_fid)
             .service(
                 web::scope("/api/v1")
                     .service(api_view_get_file_fid_name)
                     .service(api_view_get_file_fid_name_info)
+                    .service(api_view_get_file_fid_name_entry)
+                    .service(api_view_get_file_fid_name_thumbnail)
                     .service(api_view_get_file_fid)
                     .service(api_view_delete_file_fid)
+                    .service(api_view_put_file_fid_slug)
+                    .service(api_view_patch_file_fid)
+                    .service(api_view_get_files)
+                    .service(api_view_get_collection_id)
+                    .service(api_view_get_collection_id_archive)
+                    .service(api_view_delete_collection_id)
                     .service(api_view_post_file)
+                    .service(api_view_put_file_name)
+                    .service(api_view_options_uploads)
+                    .service(api_view_post_uploads)
+                    .service(api_view_head_upload_id)
+                    .service(api_view_patch_upload_id)
+                    .service(api_view_delete_upload_id)
                     .service(api_view_post_auth_token)
                     .service(api_view_get_auth_token)
                     .service(api_view_delete_auth_token_name),
@@ -104,6 +141,29 @@ async fn main() -> Result<(), Error> {
     Ok(())
 }
 
+/// Run an admin command instead of the server
+async fn run_command(state: &AppState, args: &[String]) -> Result<(), Error> {
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["rotate-master-key"] => {
+            state.rotate_master_key().await?;
+            Ok(())
+        }
+        ["scrub", flags @ ..] if flags.iter().all(|flag| *flag == "--repair") => {
+            let report = state.scrub(!flags.is_empty()).await?;
+            match report.issues.len() - report.repaired {
+                0 => Ok(()),
+                left => Err(Error::ScrubIssues(left)),
+            }
+        }
+        _ => Err(Error::UnknownCommand(args.join(" "))),
+    }
+}
+
 fn setup_logging(_logfile: Option<PathBuf>) {
     let ll_self = log::LevelFilter::Trace;
     env_logger::builder()
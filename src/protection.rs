@@ -0,0 +1,108 @@
+//! Password protection of single files.
+//!
+//! A file with a password can only be downloaded or looked at by someone who knows the
+//! password, or by its uploader. The API takes the password from the
+//! [`File-Password`](FILE_PASSWORD_HEADER) header or the `password` query parameter, the web
+//! frontend has an unlock form which remembers unlocked files in the session.
+
+use actix_session::Session;
+use actix_web::HttpRequest;
+use argon2::password_hash::SaltString;
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::db::types::RawFileID;
+use crate::errors::Error;
+use crate::files::{FILE_PASSWORD_HEADER, FileID};
+use crate::state::AppState;
+use crate::user::User;
+
+/// Session key of the list of files that were unlocked in the web frontend
+const SESSION_UNLOCKED_FILES: &str = "unlocked_files";
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordQuery {
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockForm {
+    pub password: String,
+}
+
+impl AppState {
+    pub fn hash_file_password(&self, password: &str) -> Result<String, Error> {
+        let salt = SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+        Ok(User::hash_password(password, salt.as_salt())?.to_string())
+    }
+
+    /// Check that whoever makes this request may access `fid`
+    ///
+    /// Access is granted if the file has no password, if `user` uploaded it, if it was unlocked
+    /// in the `session`, or if the correct `password` is given.
+    pub async fn check_file_access(
+        &self,
+        fid: FileID,
+        password: Option<&str>,
+        session: &Session,
+        user: Option<&User>,
+    ) -> Result<(), Error> {
+        let Some(file) = self.get_file_db_entry(fid, self.db()).await? else {
+            return Ok(());
+        };
+        let Some(stored_hash) = file.password_hash else {
+            return Ok(());
+        };
+        if user.is_some_and(|u| file.user_id == Some(u.id())) {
+            debug!("Uploader accesses password protected file {fid}");
+            return Ok(());
+        }
+        if unlocked_files(session)?.contains(&fid.inner()) {
+            return Ok(());
+        }
+
+        let Some(password) = password else {
+            return Err(Error::FilePasswordRequired);
+        };
+        let real_hash = User::load_password_hash(&stored_hash)?;
+        let salt = match real_hash.salt {
+            Some(s) => s,
+            None => return Err(Error::NoSaltStoredForFile(fid.to_string())),
+        };
+        if User::hash_password(password, salt)? != real_hash {
+            warn!("Wrong password for file {fid}");
+            return Err(Error::WrongFilePassword);
+        }
+        Ok(())
+    }
+}
+
+/// The password for a protected file, from the header or the query of `req`
+pub fn request_password(req: &HttpRequest, query: &PasswordQuery) -> Result<Option<String>, Error> {
+    if let Some(value) = req.headers().get(FILE_PASSWORD_HEADER) {
+        return Ok(Some(
+            value
+                .to_str()
+                .map_err(|e| Error::BadHeader(e.to_string()))?
+                .trim()
+                .to_string(),
+        ));
+    }
+    Ok(query.password.as_ref().map(|p| p.trim().to_string()))
+}
+
+/// Remember in the `session` that `fid` was unlocked with the correct password
+pub fn unlock_in_session(session: &Session, fid: FileID) -> Result<(), Error> {
+    let mut unlocked = unlocked_files(session)?;
+    if !unlocked.contains(&fid.inner()) {
+        unlocked.push(fid.inner());
+        session.insert(SESSION_UNLOCKED_FILES, unlocked)?;
+    }
+    Ok(())
+}
+
+fn unlocked_files(session: &Session) -> Result<Vec<RawFileID>, Error> {
+    Ok(session
+        .get::<Vec<RawFileID>>(SESSION_UNLOCKED_FILES)?
+        .unwrap_or_default())
+}
@@ -0,0 +1,236 @@
+use std::collections::{HashMap, HashSet};
+
+use log::{debug, info, warn};
+use sea_orm::{ColumnTrait as _, EntityTrait as _, QueryFilter as _, QuerySelect as _};
+use serde::Serialize;
+
+use crate::db::schema::file::{Column as FileC, Entity as FileE};
+use crate::errors::Error;
+use crate::state::AppState;
+use crate::user::{User, UserID};
+
+/// How much storage is used, and how much may be used
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageUsage {
+    pub used: u64,
+    pub quota: u64,
+    /// human readable `used`
+    pub used_human: String,
+    /// human readable `quota`
+    pub quota_human: String,
+    /// used percentage of the quota, 0 to 100
+    pub percent: u8,
+}
+
+impl StorageUsage {
+    fn new(used: u64, quota: u64) -> Self {
+        Self {
+            used,
+            quota,
+            used_human: human_bytes::human_bytes(used as f64),
+            quota_human: human_bytes::human_bytes(quota as f64),
+            percent: (used.saturating_mul(100) / quota.max(1)).min(100) as u8,
+        }
+    }
+
+    /// Would storing `additional` bytes go over the quota?
+    pub fn would_exceed(&self, additional: u64) -> bool {
+        self.used.saturating_add(additional) > self.quota
+    }
+}
+
+/// Storage that uploads which passed the quota check will take up once they are committed
+#[derive(Debug, Default)]
+pub struct QuotaReservations {
+    next_id: u64,
+    /// Bytes charged to the uploader and to the instance, by the id of the reservation
+    reserved: HashMap<u64, (Option<UserID>, u64, u64)>,
+}
+
+impl QuotaReservations {
+    /// Bytes reserved for `user`, or for the whole instance if `None`
+    fn reserved(&self, user: Option<&User>) -> u64 {
+        self.reserved
+            .values()
+            .map(|&(uid, user_size, size)| match user {
+                Some(user) if uid == Some(user.id()) => user_size,
+                Some(_) => 0,
+                None => size,
+            })
+            .sum()
+    }
+}
+
+/// Storage that is kept free for an upload until it is committed, from
+/// [`AppState::reserve_storage`]
+///
+/// Dropping it releases the storage again, by then the file should be in the DB.
+pub struct QuotaReservation<'a> {
+    state: &'a AppState,
+    id: u64,
+}
+
+impl Drop for QuotaReservation<'_> {
+    fn drop(&mut self) {
+        self.state
+            .quota_reservations
+            .lock()
+            .expect("the quota reservations are never poisoned")
+            .reserved
+            .remove(&self.id);
+    }
+}
+
+impl AppState {
+    /// Bytes that all files of `user` take up, or that all files together take up if `None`
+    ///
+    /// Files that share a blob are only counted once.
+    async fn storage_used(&self, user: Option<&User>) -> Result<u64, Error> {
+        let mut query = FileE::find()
+            .select_only()
+            .column(FileC::Blob)
+            .column(FileC::Size);
+        if let Some(user) = user {
+            query = query.filter(FileC::UserId.eq(user.id()));
+        }
+        let files: Vec<(Option<String>, i64)> = query.into_tuple().all(self.db()).await?;
+        let mut blobs = HashSet::new();
+        Ok(files
+            .into_iter()
+            .filter(|(blob, _)| blob.as_ref().is_none_or(|blob| blobs.insert(blob.clone())))
+            .map(|(_, size)| size as u64)
+            .sum())
+    }
+
+    pub async fn storage_usage_user(&self, user: &User) -> Result<StorageUsage, Error> {
+        Ok(StorageUsage::new(
+            self.storage_used(Some(user)).await?,
+            self.config().files.max_storage_per_user * 1024,
+        ))
+    }
+
+    pub async fn storage_usage_total(&self) -> Result<StorageUsage, Error> {
+        Ok(StorageUsage::new(
+            self.storage_used(None).await?,
+            self.config().files.max_storage * 1024,
+        ))
+    }
+
+    /// Check that `size` more bytes could be stored for `user`, before the upload is received
+    ///
+    /// Anonymous uploads are only limited by the storage of the whole instance. If the instance
+    /// is full but `delete_old_files` is set, this passes without evicting anything, the files
+    /// are only evicted by [`reserve_storage`](Self::reserve_storage) once the upload is
+    /// complete.
+    ///
+    /// If the data is known to have the checksum `sha256`, it is not charged again where its
+    /// blob is already stored.
+    pub async fn check_storage_quota(
+        &self,
+        user: Option<&User>,
+        size: u64,
+        sha256: Option<&str>,
+    ) -> Result<(), Error> {
+        let (user_size, size) = self.charged_size(user, size, sha256).await?;
+        self.check_user_quota(user, user_size).await?;
+        let usage = self.instance_usage().await?;
+        if usage.would_exceed(size)
+            && !(self.config().files.delete_old_files && size <= usage.quota)
+        {
+            warn!("Upload would exceed the storage of the instance");
+            return Err(Error::StorageFull);
+        }
+        Ok(())
+    }
+
+    /// Make sure that `size` more bytes can be stored for `user`, and keep them reserved until
+    /// the returned [`QuotaReservation`] is dropped
+    ///
+    /// Like [`check_storage_quota`](Self::check_storage_quota), but if the instance is full
+    /// and `delete_old_files` is set, old files are evicted to make room. Uploads that are
+    /// checked at the same time are counted against the quota together.
+    pub async fn reserve_storage(
+        &self,
+        user: Option<&User>,
+        size: u64,
+        sha256: Option<&str>,
+    ) -> Result<QuotaReservation<'_>, Error> {
+        let _guard = self.quota_lock.lock().await;
+        let (user_size, size) = self.charged_size(user, size, sha256).await?;
+        self.check_user_quota(user, user_size).await?;
+
+        let mut usage = self.instance_usage().await?;
+        // evicting files can not make room for something larger than the whole storage
+        if usage.would_exceed(size) && self.config().files.delete_old_files && size <= usage.quota {
+            info!("Upload would exceed the storage of the instance, evicting old files");
+            self.evict_files(usage.used + size - usage.quota).await?;
+            usage = self.instance_usage().await?;
+        }
+        if usage.would_exceed(size) {
+            warn!("Upload would exceed the storage of the instance");
+            return Err(Error::StorageFull);
+        }
+
+        let mut reservations = self
+            .quota_reservations
+            .lock()
+            .expect("the quota reservations are never poisoned");
+        let id = reservations.next_id;
+        reservations.next_id += 1;
+        reservations
+            .reserved
+            .insert(id, (user.map(|u| u.id()), user_size, size));
+        Ok(QuotaReservation { state: self, id })
+    }
+
+    /// The bytes that `size` bytes with the checksum `sha256` are charged to `user` and to
+    /// the instance
+    async fn charged_size(
+        &self,
+        user: Option<&User>,
+        size: u64,
+        sha256: Option<&str>,
+    ) -> Result<(u64, u64), Error> {
+        let (mut user_size, mut size) = (size, size);
+        if let Some(sha256) = sha256 {
+            if self.blob_referenced(sha256, None).await? {
+                size = 0;
+            }
+            if let Some(user) = user
+                && self.blob_referenced(sha256, Some(user)).await?
+            {
+                user_size = 0;
+            }
+        }
+        Ok((user_size, size))
+    }
+
+    async fn check_user_quota(&self, user: Option<&User>, size: u64) -> Result<(), Error> {
+        let Some(user) = user else {
+            return Ok(());
+        };
+        let mut usage = self.storage_usage_user(user).await?;
+        usage.used += self.reserved_storage(Some(user));
+        debug!("storage usage of {}: {usage:?}", user.email());
+        if usage.would_exceed(size) {
+            warn!("Upload would exceed the storage quota of {}", user.email());
+            return Err(Error::UserQuotaExceeded(usage.quota_human));
+        }
+        Ok(())
+    }
+
+    /// The storage usage of the instance, including the reservations of uploads
+    async fn instance_usage(&self) -> Result<StorageUsage, Error> {
+        let mut usage = self.storage_usage_total().await?;
+        usage.used += self.reserved_storage(None);
+        debug!("storage usage of the instance: {usage:?}");
+        Ok(usage)
+    }
+
+    fn reserved_storage(&self, user: Option<&User>) -> u64 {
+        self.quota_reservations
+            .lock()
+            .expect("the quota reservations are never poisoned")
+            .reserved(user)
+    }
+}
@@ -0,0 +1,455 @@
+//! Checking that the DB and the storage agree.
+//!
+//! Crashes and bugs can leave data in the storage that no file belongs to, or files whose data
+//! is gone. The scrub finds these, and with `repair` removes what can't be used anymore:
+//!
+//! - objects that belong to no file or blob, once they are older than the grace period
+//! - objects below a file that are not its data or thumbnails
+//! - files and blobs whose data is missing
+//! - blobs that are counted more or less often than they are referenced
+//! - with `verify_checksums`, files and blobs whose data does not match their checksum
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use log::{info, warn};
+use sea_orm::{ActiveModelTrait as _, EntityTrait as _, ModelTrait as _};
+
+use crate::db::schema::blob::{self, Entity as BlobE};
+use crate::errors::Error;
+use crate::files::FileID;
+use crate::state::AppState;
+use crate::storage;
+
+/// A problem found by [`AppState::scrub`]
+#[derive(Debug, Clone)]
+pub enum ScrubIssue {
+    /// An object that belongs to no file and no blob
+    OrphanObject { key: String },
+    /// An object below a file that is not its data or a thumbnail
+    StrayObject { fid: FileID, key: String },
+    /// A file whose data is not in the storage
+    MissingData { fid: FileID },
+    /// An old file without a name in the DB that has several data files
+    AmbiguousData { fid: FileID, keys: Vec<String> },
+    /// A blob that is not in the storage
+    MissingBlob { sha256: String },
+    /// A blob whose reference count does not match the files that reference it
+    WrongReferenceCount {
+        sha256: String,
+        recorded: i32,
+        actual: i32,
+    },
+    /// Stored data that does not have the checksum of its file or blob
+    ChecksumMismatch {
+        key: String,
+        expected: String,
+        actual: String,
+    },
+    /// Stored data that could not be read to verify its checksum, only repaired if it could
+    /// not be decrypted
+    Unreadable { key: String, error: String },
+}
+
+/// What [`AppState::scrub`] found
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    pub issues: Vec<ScrubIssue>,
+    /// How many of the `issues` were repaired
+    pub repaired: usize,
+}
+
+impl Display for ScrubIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OrphanObject { key } => write!(f, "Object {key} belongs to no file"),
+            Self::StrayObject { fid, key } => {
+                write!(f, "Object {key} is not the data of file {fid}")
+            }
+            Self::MissingData { fid } => write!(f, "Data of file {fid} is missing"),
+            Self::AmbiguousData { fid, keys } => {
+                write!(f, "File {fid} has several data files: {}", keys.join(", "))
+            }
+            Self::MissingBlob { sha256 } => write!(f, "Blob {sha256} is missing"),
+            Self::WrongReferenceCount {
+                sha256,
+                recorded,
+                actual,
+            } => write!(
+                f,
+                "Blob {sha256} is counted {recorded} times, but referenced {actual} times"
+            ),
+            Self::ChecksumMismatch {
+                key,
+                expected,
+                actual,
+            } => write!(f, "Object {key} has checksum {actual}, expected {expected}"),
+            Self::Unreadable { key, error } => write!(f, "Object {key} can't be read: {error}"),
+        }
+    }
+}
+
+impl ScrubReport {
+    fn found(&mut self, issue: ScrubIssue) {
+        warn!("Scrub: {issue}");
+        self.issues.push(issue);
+    }
+
+    fn repaired(&mut self) {
+        self.repaired += 1;
+    }
+}
+
+impl AppState {
+    /// Check that the DB and the storage agree, see the [module docs](self)
+    pub async fn scrub(&self, repair: bool) -> Result<ScrubReport, Error> {
+        info!("Scrubbing the storage, repair: {repair}");
+        let config = &self.config().files.scrub;
+        let mut report = ScrubReport::default();
+        // the DB first, so that all data of these files and blobs is already stored when
+        // listing the storage, newer objects are protected by the grace period
+        let files = self.files().await?;
+        let blobs: HashSet<String> = BlobE::find()
+            .all(self.db())
+            .await?
+            .into_iter()
+            .map(|blob| blob.sha256)
+            .collect();
+        let keys: HashSet<String> = self.storage().list("").await?.into_iter().collect();
+        // keys that are the data of a file or blob, or below a file
+        let mut known: HashSet<String> = HashSet::new();
+
+        let mut keys_by_fid: HashMap<&str, Vec<&String>> = HashMap::new();
+        for key in &keys {
+            if let Some((first, _)) = key.split_once('/') {
+                keys_by_fid.entry(first).or_default().push(key);
+            }
+        }
+        let cutoff = chrono::Utc::now().naive_utc() - config.grace_period.0;
+
+        for file in files {
+            let fid: FileID = file.id.into();
+            let below = keys_by_fid
+                .remove(fid.inner().to_string().as_str())
+                .unwrap_or_default();
+            known.extend(below.iter().map(|key| key.to_string()));
+            let data_prefix = storage::key_fid_data(fid);
+            let data: Vec<&String> = below
+                .iter()
+                .copied()
+                .filter(|key| key.starts_with(&data_prefix))
+                .collect();
+            let (has_data, data_key) = match (&file.blob, &file.name) {
+                (Some(sha256), _) => (
+                    blobs.contains(sha256) && keys.contains(&storage::key_blob(sha256)),
+                    None,
+                ),
+                (None, Some(name)) => {
+                    let key = storage::key_fid_datafile(fid, name);
+                    (keys.contains(&key), Some(key))
+                }
+                (None, None) => match data.as_slice() {
+                    [key] => (true, Some(key.to_string())),
+                    [] => (false, None),
+                    _ => {
+                        report.found(ScrubIssue::AmbiguousData {
+                            fid,
+                            keys: data.iter().map(|key| key.to_string()).collect(),
+                        });
+                        continue;
+                    }
+                },
+            };
+
+            let thumbnail_prefix = storage::key_fid_thumbnails(fid);
+            for key in below {
+                if Some(key) != data_key.as_ref()
+                    && !key.starts_with(&thumbnail_prefix)
+                    && self.storage().stat(key).await?.time_modified < cutoff
+                {
+                    report.found(ScrubIssue::StrayObject {
+                        fid,
+                        key: key.clone(),
+                    });
+                    if repair {
+                        self.storage().delete(key).await?;
+                        report.repaired();
+                    }
+                }
+            }
+            if !has_data {
+                report.found(ScrubIssue::MissingData { fid });
+                if repair {
+                    // the reference to its blob is corrected below
+                    file.delete(self.db()).await?;
+                    report.repaired();
+                }
+            }
+        }
+
+        known.extend(blobs.iter().map(|sha256| storage::key_blob(sha256)));
+        self.scrub_blobs(repair, &mut report).await?;
+
+        for key in keys.difference(&known) {
+            if self.storage().stat(key).await?.time_modified < cutoff {
+                report.found(ScrubIssue::OrphanObject { key: key.clone() });
+                if repair {
+                    self.storage().delete(key).await?;
+                    report.repaired();
+                }
+            }
+        }
+
+        if config.verify_checksums {
+            self.scrub_checksums(&keys, repair, &mut report).await?;
+        }
+
+        info!(
+            "Scrub finished: {} problems found, {} repaired",
+            report.issues.len(),
+            report.repaired
+        );
+        Ok(report)
+    }
+
+    /// Check that every blob exists and is counted as often as it is referenced
+    ///
+    /// Blob objects without a blob in the DB are left to the check for orphans. While this
+    /// holds the blob lock, no file can be committed and no blob can be released.
+    async fn scrub_blobs(&self, repair: bool, report: &mut ScrubReport) -> Result<(), Error> {
+        let _guard = self.blob_lock.lock().await;
+        let keys: HashSet<String> = self
+            .storage()
+            .list(&storage::key_blob(""))
+            .await?
+            .into_iter()
+            .collect();
+        let mut references: HashMap<String, i32> = HashMap::new();
+        for sha256 in self.files().await?.into_iter().filter_map(|file| file.blob) {
+            *references.entry(sha256).or_default() += 1;
+        }
+
+        for blob in BlobE::find().all(self.db()).await? {
+            let sha256 = blob.sha256.clone();
+            let actual = references.get(&sha256).copied().unwrap_or(0);
+            if !keys.contains(&storage::key_blob(&sha256)) {
+                report.found(ScrubIssue::MissingBlob {
+                    sha256: sha256.clone(),
+                });
+                // its files were removed as missing their data
+                if repair {
+                    blob.delete(self.db()).await?;
+                    report.repaired();
+                }
+                continue;
+            }
+            if actual == blob.reference_count {
+                continue;
+            }
+            report.found(ScrubIssue::WrongReferenceCount {
+                sha256,
+                recorded: blob.reference_count,
+                actual,
+            });
+            if !repair {
+                continue;
+            }
+            if actual == 0 {
+                self.storage()
+                    .delete(&storage::key_blob(&blob.sha256))
+                    .await?;
+                blob.delete(self.db()).await?;
+            } else {
+                let mut blob: blob::ActiveModel = blob.into();
+                blob.reference_count = sea_orm::ActiveValue::Set(actual);
+                blob.update(self.db()).await?;
+            }
+            report.repaired();
+        }
+        Ok(())
+    }
+
+    /// Hash all stored data and compare it to the checksums in the DB
+    ///
+    /// Files with data that does not match are removed when repairing.
+    async fn scrub_checksums(
+        &self,
+        keys: &HashSet<String>,
+        repair: bool,
+        report: &mut ScrubReport,
+    ) -> Result<(), Error> {
+        let mut blob_ok: HashMap<String, bool> = HashMap::new();
+        for blob in BlobE::find().all(self.db()).await? {
+            let data = self.blob_data(&blob)?;
+            // missing data was already reported
+            if !keys.contains(&data.key) {
+                continue;
+            }
+            let ok = self
+                .scrub_checksum(&data.key, self.hash_data(&data).await, &blob.sha256, report)
+                .await;
+            blob_ok.insert(blob.sha256, ok);
+        }
+
+        // a blob is one issue, repaired once all of its files are removed
+        let mut removed_blobs: HashSet<String> = HashSet::new();
+        for file in self.files().await? {
+            let ok = match (&file.blob, &file.name, &file.sha256) {
+                (Some(sha256), _, _) => blob_ok.get(sha256).copied().unwrap_or(true),
+                (None, Some(name), Some(sha256))
+                    if keys.contains(&storage::key_fid_datafile(file.id.into(), name)) =>
+                {
+                    let fid: FileID = file.id.into();
+                    let data = self.stored_data(fid, name).await?;
+                    self.scrub_checksum(&data.key, self.hash_data(&data).await, sha256, report)
+                        .await
+                }
+                // old files are hashed on startup
+                _ => true,
+            };
+            if !ok && repair {
+                let blob = file.blob.clone();
+                self.remove_file(file).await?;
+                match blob {
+                    Some(sha256) => {
+                        removed_blobs.insert(sha256);
+                    }
+                    None => report.repaired(),
+                }
+            }
+        }
+        report.repaired += removed_blobs.len();
+        Ok(())
+    }
+
+    /// Report `hashes` of the data at `key` if they are not `expected`
+    ///
+    /// Returns `false` only if the data is wrong, not if it could not be read because of
+    /// the storage.
+    async fn scrub_checksum(
+        &self,
+        key: &str,
+        hashes: Result<crate::files::FileHashes, Error>,
+        expected: &str,
+        report: &mut ScrubReport,
+    ) -> bool {
+        match hashes {
+            Ok(hashes) if hashes.sha256 == expected => true,
+            Ok(hashes) => {
+                report.found(ScrubIssue::ChecksumMismatch {
+                    key: key.to_string(),
+                    expected: expected.to_string(),
+                    actual: hashes.sha256,
+                });
+                false
+            }
+            Err(e) => {
+                report.found(ScrubIssue::Unreadable {
+                    key: key.to_string(),
+                    error: e.to_string(),
+                });
+                // encrypted data that was changed can't be decrypted anymore
+                !is_decryption_error(&e)
+            }
+        }
+    }
+}
+
+/// Is `e` an [`Error::Decryption`], even one that was passed through a decompressor?
+fn is_decryption_error(e: &Error) -> bool {
+    match e {
+        Error::Decryption => true,
+        Error::IO(io) => io
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<Error>())
+            .is_some_and(is_decryption_error),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sea_orm::{ActiveValue, PaginatorTrait as _};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::db::schema::file::{self, Entity as FileE};
+
+    #[tokio::test]
+    async fn test_scrub_shared_corrupt_blob() {
+        let dir = std::env::temp_dir().join(format!("tempfiles-scrub-{}", std::process::id()));
+        let config: Config = toml::from_str(&format!(
+            r#"
+            [files]
+            max_size_kb_anon = 1024
+            max_size_kb_users = 1024
+            max_storage_per_user = 1024
+            max_storage = 1024
+            storage_dir = "{dir}/storage"
+            staging_dir = "{dir}/staging"
+            default_expiration_days = 1
+            delete_old_files = false
+            scrub = {{ verify_checksums = true }}
+
+            [accounts]
+            allow_anon = true
+            allow_registration = true
+
+            [service]
+            secret = "secret"
+            rate_limit_window_ms = 1000
+            rate_limit_max_uploads = 1
+            db_sqlite = "{dir}/db.sqlite"
+            bind = "127.0.0.1:0"
+            base_url = "http://localhost"
+            data_dir = "./data"
+            clear_interval = 1
+            "#,
+            dir = dir.display()
+        ))
+        .unwrap();
+        let state = AppState::new(&config).await.unwrap();
+
+        // two files share a blob whose data does not match its checksum
+        let sha256 = "0".repeat(64);
+        state
+            .storage()
+            .put_bytes(&storage::key_blob(&sha256), "corrupt".into())
+            .await
+            .unwrap();
+        BlobE::insert(blob::ActiveModel {
+            sha256: ActiveValue::Set(sha256.clone()),
+            size: ActiveValue::Set(7),
+            reference_count: ActiveValue::Set(2),
+            ..Default::default()
+        })
+        .exec(state.db())
+        .await
+        .unwrap();
+        for id in [1, 2] {
+            FileE::insert(file::ActiveModel {
+                id: ActiveValue::Set(id),
+                expiration_time: ActiveValue::Set(
+                    chrono::Utc::now().naive_utc() + chrono::TimeDelta::hours(1),
+                ),
+                size: ActiveValue::Set(7),
+                download_count: ActiveValue::Set(0),
+                encrypted: ActiveValue::Set(false),
+                name: ActiveValue::Set(Some(format!("{id}.txt"))),
+                sha256: ActiveValue::Set(Some(sha256.clone())),
+                blob: ActiveValue::Set(Some(sha256.clone())),
+                ..Default::default()
+            })
+            .exec(state.db())
+            .await
+            .unwrap();
+        }
+
+        let report = state.scrub(true).await.unwrap();
+        assert_eq!(report.issues.len(), 1, "{:?}", report.issues);
+        assert_eq!(report.repaired, 1);
+        assert_eq!(FileE::find().count(state.db()).await.unwrap(), 0);
+        assert_eq!(BlobE::find().count(state.db()).await.unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
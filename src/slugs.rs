@@ -0,0 +1,134 @@
+//! Custom names of files, like `/file/team-roadmap`
+//!
+//! Registered users can give their files a slug when uploading or later. Slugs are resolved by
+//! the same routes as [`FileID`]s and share their namespace: a slug is never the id of another
+//! file, and new ids are never a slug that is taken. A slug is released when its file is removed.
+
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use log::info;
+use sea_orm::{
+    ActiveModelTrait as _, ColumnTrait as _, ConnectionTrait, EntityTrait as _, QueryFilter as _,
+};
+
+use crate::db::schema::file::{self, Column as FileC, Entity as FileE};
+use crate::errors::Error;
+use crate::files::FileID;
+use crate::state::AppState;
+use crate::user::User;
+
+/// How long a slug may be
+pub const SLUG_LENGTHS: RangeInclusive<usize> = 3..=64;
+/// Slugs that could be mistaken for pages of the site
+pub const RESERVED_SLUGS: &[&str] = &[
+    "about", "admin", "api", "file", "files", "index", "login", "logout", "register", "settings",
+    "static", "unlock", "upload", "uploads",
+];
+
+/// Check that `raw` can be a slug, returns it in lowercase
+///
+/// Slugs are made of letters, digits and dashes, but not only of digits, which would be taken
+/// for old file ids.
+pub fn parse_slug(raw: &str) -> Result<String, Error> {
+    let slug = raw.trim().to_ascii_lowercase();
+    let bad = || Error::BadSlug(raw.to_string());
+    if !SLUG_LENGTHS.contains(&slug.len())
+        || !slug
+            .bytes()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-')
+        || slug.starts_with('-')
+        || slug.ends_with('-')
+        || slug.bytes().all(|c| c.is_ascii_digit())
+    {
+        return Err(bad());
+    }
+    if RESERVED_SLUGS.contains(&slug.as_str()) {
+        return Err(Error::ReservedSlug(slug));
+    }
+    Ok(slug)
+}
+
+impl AppState {
+    /// Find the file that `s` stands for in a url, a slug or a [`FileID`]
+    pub async fn resolve_fid(&self, s: &str) -> Result<FileID, Error> {
+        if parse_slug(s).is_ok_and(|slug| slug == s)
+            && let Some(file) = self.find_slug(s, self.db()).await?
+        {
+            return Ok(file.id.into());
+        }
+        FileID::from_str(s).map_err(|e| match parse_slug(s) {
+            Ok(_) => Error::FileNotFound,
+            Err(_) => e,
+        })
+    }
+
+    /// The file that has the slug `slug`, if any
+    pub async fn find_slug(
+        &self,
+        slug: &str,
+        db: &impl ConnectionTrait,
+    ) -> Result<Option<file::Model>, Error> {
+        Ok(FileE::find().filter(FileC::Slug.eq(slug)).one(db).await?)
+    }
+
+    /// Fail with [`Error::SlugTaken`] if `slug` belongs to a file or is the id of one
+    pub async fn check_slug_available(
+        &self,
+        slug: &str,
+        db: &impl ConnectionTrait,
+    ) -> Result<(), Error> {
+        let is_fid = match FileID::from_str(slug) {
+            Ok(fid) => self.get_file_db_entry(fid, db).await?.is_some(),
+            Err(_) => false,
+        };
+        if is_fid || self.find_slug(slug, db).await?.is_some() {
+            return Err(Error::SlugTaken(slug.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Give the file `fid` of `user` a new slug, or remove its slug with `None`
+    ///
+    /// Returns the new slug, normalized by [`parse_slug`].
+    pub async fn set_slug(
+        &self,
+        user: &User,
+        fid: FileID,
+        slug: Option<&str>,
+    ) -> Result<Option<String>, Error> {
+        let file = self.get_owned_file(user, fid).await?;
+        let slug = slug.map(parse_slug).transpose()?;
+        if slug == file.slug {
+            return Ok(slug);
+        }
+        if let Some(slug) = &slug {
+            self.check_slug_available(slug, self.db()).await?;
+        }
+        info!(
+            "Changing the slug of {fid} from {:?} to {slug:?}",
+            file.slug
+        );
+        let mut file: file::ActiveModel = file.into();
+        file.slug = sea_orm::ActiveValue::Set(slug.clone());
+        file.update(self.db()).await?;
+        Ok(slug)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_slug;
+
+    #[test]
+    fn test_parse_slug() {
+        assert_eq!(parse_slug("Team-Roadmap").unwrap(), "team-roadmap");
+        assert_eq!(parse_slug("q3-2025").unwrap(), "q3-2025");
+        assert!(parse_slug("ab").is_err());
+        assert!(parse_slug("12345").is_err());
+        assert!(parse_slug("-roadmap").is_err());
+        assert!(parse_slug("road map").is_err());
+        assert!(parse_slug("roadmap.pdf").is_err());
+        assert!(parse_slug("login").is_err());
+    }
+}
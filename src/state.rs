@@ -1,27 +1,51 @@
-use std::io::Write;
+use std::collections::HashSet;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 use log::{debug, error, info, warn};
 use migrations::{MigratorTrait, SchemaManager};
-use rand::{Rng, SeedableRng};
-use sea_orm::{Database, DatabaseConnection, EntityTrait as _, ModelTrait};
+use rand::SeedableRng;
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    ActiveModelTrait as _, ColumnTrait as _, Condition, ConnectionTrait, Database,
+    DatabaseConnection, EntityTrait as _, QueryFilter as _, TransactionTrait as _,
+};
 use tokio::sync::Mutex;
 
-use crate::config::Config;
+use crate::config::{Config, ExpirationLimits, HumanDuration};
 use crate::db::schema;
-use crate::db::schema::file::{Entity as FileE, Model as FileM};
+use crate::db::schema::blob::Entity as BlobE;
+use crate::db::schema::file::{Column as FileC, Entity as FileE, Model as FileM};
 use crate::db::schema::user::Entity as UserE;
-use crate::errors::Error;
-use crate::files::{FileID, FileInfos};
-use crate::user::User;
+use crate::errors::{ConfigError, Error};
+use crate::files::{FID_LENGTHS, FileID, FileInfos, NewFile, UploadOptions};
+use crate::quota::QuotaReservations;
+use crate::slugs;
+use crate::storage::{self, StorageBackend};
+use crate::thumbnails::{THUMBNAIL_QUEUE_LENGTH, ThumbnailSize};
+use crate::user::{User, UserKind};
 
 const MAX_FID_RETRIES: u32 = 20;
+/// How many bytes of a file are looked at to detect its content type
+pub(crate) const CONTENT_TYPE_DETECTION_LENGTH: u64 = 1 << 20;
 
 pub struct AppState {
     pub(crate) db: DatabaseConnection, // NOTE: closed on drop
     pub(crate) config: Config,
     pub(crate) csprng: Mutex<rand::rngs::StdRng>,
+    /// Held while the references to blobs change
+    pub(crate) blob_lock: Mutex<()>,
+    /// Held while checking the storage quota, so that concurrent uploads can't all pass it
+    pub(crate) quota_lock: Mutex<()>,
+    /// Storage of uploads that passed the quota check but are not committed yet
+    pub(crate) quota_reservations: std::sync::Mutex<QuotaReservations>,
+    /// Ids of resumable uploads that a request is working on, see [`tus`](crate::tus)
+    pub(crate) busy_uploads: std::sync::Mutex<HashSet<String>>,
+    storage: Box<dyn StorageBackend>,
+    /// Files whose thumbnails should be made, see [`thumbnails`](crate::thumbnails)
+    pub(crate) thumbnail_queue: tokio::sync::mpsc::Sender<FileID>,
+    /// Taken by the thumbnail worker when it starts
+    pub(crate) thumbnail_jobs: Mutex<Option<tokio::sync::mpsc::Receiver<FileID>>>,
     template_reloader: minijinja_autoreload::AutoReloader,
 }
 
@@ -46,11 +70,19 @@ impl AppState {
         let db_url = format!("sqlite:{}?mode=rwc", config.service.db_sqlite);
         debug!("DB url: {db_url}");
         let db = Database::connect(db_url).await?;
+        let (thumbnail_queue, thumbnail_jobs) = tokio::sync::mpsc::channel(THUMBNAIL_QUEUE_LENGTH);
 
         let a = AppState {
             db,
             config: config.clone(),
             csprng: Mutex::new(csprng),
+            blob_lock: Mutex::new(()),
+            quota_lock: Mutex::new(()),
+            quota_reservations: std::sync::Mutex::new(QuotaReservations::default()),
+            busy_uploads: std::sync::Mutex::new(HashSet::new()),
+            storage: storage::from_config(&config.files)?,
+            thumbnail_queue,
+            thumbnail_jobs: Mutex::new(Some(thumbnail_jobs)),
             template_reloader,
         };
         a.run_migrations_if_needed().await?;
@@ -67,19 +99,21 @@ impl AppState {
     }
 
     async fn validate(&self) -> Result<(), Error> {
-        info!("validating file storage...");
-        if !self.storage_dir().exists() {
-            std::fs::create_dir_all(self.storage_dir())?;
-        }
-        if !self.storage_dir().is_dir() {
-            return Err(Error::StorageDirNotADir);
-        }
+        info!("validating the config...");
+        self.validate_config_base_url()?;
+        self.validate_config_encryption()?;
+        self.validate_config_id_length()?;
 
-        self.validate_make_testfile()?;
+        info!("validating file storage...");
+        self.storage().validate().await?;
+        self.validate_make_testfile().await?;
+        self.validate_staging_dir().await?;
 
         info!("validating the database...");
         self.db().ping().await?;
         self.validate_db_tables_exist().await?;
+        self.validate_file_metadata().await?;
+        self.rollback_interrupted_uploads().await?;
 
         info!("finished validations");
 
@@ -99,21 +133,20 @@ impl AppState {
         Ok(self.template_reloader.acquire_env()?)
     }
 
-    pub fn storage_dir(&self) -> PathBuf {
-        std::path::PathBuf::from_str(&self.config.files.storage_dir)
-            .expect("Infalliable from_str failed")
+    pub fn storage(&self) -> &dyn StorageBackend {
+        self.storage.as_ref()
     }
 
     pub async fn csprng(&self) -> tokio::sync::MutexGuard<'_, rand::prelude::StdRng> {
         self.csprng.lock().await
     }
 
-    pub async fn new_fid(&self) -> FileID {
+    pub async fn new_fid(&self) -> Result<FileID, Error> {
         let mut fid: FileID;
         for _ in 0..MAX_FID_RETRIES {
-            fid = self.csprng().await.random();
-            if !self.has_fid(fid).await {
-                return fid;
+            fid = FileID::random(&mut *self.csprng().await, self.config().files.id_length);
+            if !self.has_fid(fid).await? {
+                return Ok(fid);
             }
         }
         panic!(
@@ -121,112 +154,91 @@ impl AppState {
         )
     }
 
-    pub async fn has_fid(&self, fid: FileID) -> bool {
-        let mut path = self.storage_dir();
-        path.push(fid.to_string());
-
-        path.exists()
-    }
-
-    pub fn upload_dir_for_fid(
-        &self,
-        fid: impl Into<FileID>,
-        create: bool,
-    ) -> Result<PathBuf, Error> {
-        let fid: FileID = fid.into();
-        let mut p = self.storage_dir();
-        p.push(fid.to_string());
-        if create {
-            if let Err(e) = std::fs::create_dir(&p) {
-                if !matches!(e.kind(), std::io::ErrorKind::AlreadyExists) {
-                    return Err(e.into());
-                }
-            }
-        }
-        Ok(p)
-    }
-
-    pub fn upload_datafile_for_fid(
-        &self,
-        fid: FileID,
-        name: &str,
-        create_dirs: bool,
-    ) -> Result<PathBuf, Error> {
-        let mut p = self.storage_dir();
-        p.push(fid.to_string());
-        p.push("data");
-        if create_dirs {
-            if let Err(e) = std::fs::create_dir_all(&p) {
-                if !matches!(e.kind(), std::io::ErrorKind::AlreadyExists) {
-                    return Err(e.into());
-                }
-            }
-        }
-        p.push(name);
-        Ok(p)
+    pub async fn has_fid(&self, fid: FileID) -> Result<bool, Error> {
+        // files in blobs have nothing below their own key
+        Ok(self.get_file_db_entry(fid, self.db()).await?.is_some()
+            || self.find_slug(&fid.to_string(), self.db()).await?.is_some()
+            || !self
+                .storage()
+                .list(&storage::key_fid(fid))
+                .await?
+                .is_empty()
+            || !self
+                .storage()
+                .list(&storage::key_staging(fid))
+                .await?
+                .is_empty())
     }
 
     pub async fn make_file_infos(&self, fid: FileID, name: &str) -> Result<FileInfos, Error> {
-        let path = &self.upload_datafile_for_fid(fid, name, false)?;
-        let flags = magic::cookie::Flags::MIME_TYPE | magic::cookie::Flags::MIME_ENCODING;
-        let cookie = magic::Cookie::open(flags)?;
-        let cookie = cookie
-            .load(&magic::cookie::DatabasePaths::default())
-            .expect("could not load database for libmagic file type detection");
-
-        Ok(FileInfos::builder()
+        let data = self.stored_data(fid, name).await?;
+        let head = self
+            .read_data(&data, Some(0..CONTENT_TYPE_DETECTION_LENGTH))
+            .await?;
+        // the stored object may be compressed
+        let meta = storage::ObjectMeta {
+            size: data.size,
+            ..self.storage().stat(&data.key).await?
+        };
+
+        let mut finfos = FileInfos::builder()
             .fid(fid)
             .name(name.to_owned())
             .url_raw(self.uri_api_file_fid_name(fid, name).to_string())
             .url_infos(self.uri_api_file_fid_name_info(fid, name).to_string())
             .url_frontend(self.uri_frontend_file_fid_name(fid, name).to_string())
-            .content_type(
-                mime::Mime::from_str(&cookie.file(path).unwrap_or("unknown".to_string()))
-                    .unwrap_or(mime::APPLICATION_OCTET_STREAM)
-                    .to_string(),
-            )
-            .filemeta(path)?
+            .content_type(detect_content_type(&head)?.to_string())
+            .objectmeta(&meta)
             .get_db_info(self.db(), fid)
             .await
             .inspect_err(|e| error!("Could not get DB info for file with id {fid}: {e}"))?
-            .build()?)
-    }
-
-    pub fn get_filename_for_fid(&self, fid: FileID) -> Result<String, Error> {
-        let mut path: PathBuf = self.upload_dir_for_fid(fid, false)?;
-        path.push("data");
-        debug!("fid path: {}", path.display());
-        if !path.exists() {
-            debug!("does not exist");
-            return Err(Error::FileNotFound);
+            .build()?;
+        finfos.url_slug = finfos
+            .slug
+            .as_deref()
+            .map(|slug| self.uri_frontend_file_slug(slug).to_string());
+        finfos.url_collection = finfos
+            .collection
+            .as_deref()
+            .map(|id| self.uri_frontend_collection_id(id).to_string());
+        if finfos.has_thumbnails() {
+            finfos.url_thumbnail = Some(
+                self.uri_api_file_fid_name_thumbnail(fid, name, ThumbnailSize::Thumbnail)
+                    .to_string(),
+            );
+            finfos.url_preview = Some(
+                self.uri_api_file_fid_name_thumbnail(fid, name, ThumbnailSize::Medium)
+                    .to_string(),
+            );
         }
+        Ok(finfos)
+    }
 
-        let count_items = path.read_dir().into_iter().count();
-        if count_items != 1 {
-            error!("items in the directory: {count_items:?}");
-            return Err(Error::NotOneFileInStorageDir(count_items));
+    pub async fn get_filename_for_fid(&self, fid: FileID) -> Result<String, Error> {
+        if let Some(file) = self.get_file_db_entry(fid, self.db()).await?
+            && let Some(name) = file.name
+        {
+            return Ok(name);
         }
-        let mut dir_ents: std::fs::ReadDir = path.read_dir()?;
-
-        let item: Result<_, std::io::Error> = dir_ents
-            .next()
-            .expect("No dirent despite count_items being 1");
-        if let Err(e) = item {
-            return Err(e.into());
+        let keys = self.storage().list(&storage::key_fid_data(fid)).await?;
+        debug!("fid keys: {keys:?}");
+        match keys.as_slice() {
+            [] => {
+                debug!("does not exist");
+                Err(Error::FileNotFound)
+            }
+            [key] => Ok(storage::key_basename(key).to_string()),
+            _ => {
+                error!("items in the data directory: {}", keys.len());
+                Err(Error::NotOneFileInStorageDir(keys.len()))
+            }
         }
-        let name = item
-            .as_ref()
-            .unwrap()
-            .file_name()
-            .to_string_lossy()
-            .to_string();
-        Ok(name)
     }
 
     pub async fn get_file_db_entry(
         &self,
         fid: FileID,
-        db: &DatabaseConnection,
+        db: &impl ConnectionTrait,
     ) -> Result<Option<schema::file::Model>, Error> {
         Ok(crate::db::schema::prelude::File::find_by_id(fid.inner())
             .one(db)
@@ -244,18 +256,110 @@ impl AppState {
         }
     }
 
+    pub fn expiration_limits(&self, user: Option<&User>) -> Result<ExpirationLimits, Error> {
+        let kind = match user {
+            None => UserKind::Anonymous,
+            Some(u) => u.kind()?,
+        };
+        Ok(self.config().files.expiration.limits(kind))
+    }
+
+    /// When a file uploaded now by `user` expires
+    ///
+    /// Without a `requested` duration, the default expiration is used, limited to what `user`
+    /// may choose.
+    pub fn expiration_time(
+        &self,
+        user: Option<&User>,
+        requested: Option<HumanDuration>,
+    ) -> Result<chrono::NaiveDateTime, Error> {
+        let limits = self.expiration_limits(user)?;
+        let expires_in = match requested {
+            Some(requested) if limits.contains(requested) => requested,
+            Some(_) => {
+                return Err(Error::ExpirationOutOfRange(
+                    limits.min.to_string(),
+                    limits.max.to_string(),
+                ));
+            }
+            None => limits.clamp(HumanDuration(self.get_expiration_offset())),
+        };
+        Ok(chrono::Utc::now().naive_utc() + expires_in.0)
+    }
+
+    /// Parse the `options` of an upload of `size` bytes by `user`
+    pub fn new_file(
+        &self,
+        user: Option<&User>,
+        options: &UploadOptions,
+        size: u64,
+    ) -> Result<NewFile, Error> {
+        let requested_expiration = options
+            .expires_in
+            .as_deref()
+            .map(HumanDuration::from_str)
+            .transpose()?;
+        let max_downloads = match options.max_downloads.as_deref() {
+            None => None,
+            Some(raw) => match raw.parse::<u32>() {
+                Ok(n) if n >= 1 && n <= i32::MAX as u32 => Some(n),
+                _ => return Err(Error::BadMaxDownloads(raw.to_string())),
+            },
+        };
+
+        let password_hash = match options.password.as_deref() {
+            Some(password) if !password.is_empty() => Some(self.hash_file_password(password)?),
+            _ => None,
+        };
+        let expected_sha256 = match options.sha256.as_deref() {
+            None => None,
+            Some(raw) if raw.len() == 64 && raw.chars().all(|c| c.is_ascii_hexdigit()) => {
+                Some(raw.to_ascii_lowercase())
+            }
+            Some(raw) => return Err(Error::BadChecksum(raw.to_string())),
+        };
+        let encrypted = match options.encrypted.as_deref() {
+            None => false,
+            Some(raw) => raw
+                .to_ascii_lowercase()
+                .parse()
+                .map_err(|_| Error::BadEncryptedFlag(raw.to_string()))?,
+        };
+        let slug = match options.slug.as_deref() {
+            None => None,
+            Some(_) if user.is_none() => return Err(Error::SlugNeedsAccount),
+            Some(raw) => Some(slugs::parse_slug(raw)?),
+        };
+
+        Ok(NewFile {
+            size,
+            expiration: self.expiration_time(user, requested_expiration)?,
+            max_downloads,
+            password_hash,
+            expected_sha256,
+            hashes: None,
+            encrypted,
+            slug,
+            collection: None,
+        })
+    }
+
     pub async fn create_file_db_entry(
         &self,
         fid: FileID,
         user: Option<&User>,
-        db: &DatabaseConnection,
+        name: &str,
+        content_type: &mime::Mime,
+        new_file: &NewFile,
+        db: &impl ConnectionTrait,
     ) -> Result<(), Error> {
         if let Some(ent) = self.get_file_db_entry(fid, db).await? {
             warn!("Tried to insert file that already existed: {}", ent.id);
             return Err(Error::FileExists);
         }
-
-        let expiration = chrono::Utc::now().naive_utc() + self.get_expiration_offset();
+        if let Some(slug) = &new_file.slug {
+            self.check_slug_available(slug, db).await?;
+        }
 
         let user_id = match user {
             Some(u) => sea_orm::ActiveValue::Set(Some(u.id())),
@@ -264,7 +368,23 @@ impl AppState {
         let file_values = schema::file::ActiveModel {
             id: sea_orm::ActiveValue::Set(fid.inner()),
             user_id,
-            expiration_time: sea_orm::ActiveValue::Set(expiration),
+            expiration_time: sea_orm::ActiveValue::Set(new_file.expiration),
+            size: sea_orm::ActiveValue::Set(new_file.size as i64),
+            creation_time: sea_orm::ActiveValue::Set(Some(chrono::Utc::now().naive_utc())),
+            download_count: sea_orm::ActiveValue::Set(0),
+            max_downloads: sea_orm::ActiveValue::Set(new_file.max_downloads.map(|n| n as i32)),
+            password_hash: sea_orm::ActiveValue::Set(new_file.password_hash.clone()),
+            sha256: sea_orm::ActiveValue::Set(new_file.hashes.as_ref().map(|h| h.sha256.clone())),
+            blake3: sea_orm::ActiveValue::Set(
+                new_file.hashes.as_ref().and_then(|h| h.blake3.clone()),
+            ),
+            name: sea_orm::ActiveValue::Set(Some(name.to_string())),
+            blob: sea_orm::ActiveValue::Set(new_file.hashes.as_ref().map(|h| h.sha256.clone())),
+            encrypted: sea_orm::ActiveValue::Set(new_file.encrypted),
+            slug: sea_orm::ActiveValue::Set(new_file.slug.clone()),
+            description: sea_orm::ActiveValue::Set(None),
+            collection_id: sea_orm::ActiveValue::Set(new_file.collection.clone()),
+            content_type: sea_orm::ActiveValue::Set(Some(content_type.to_string())),
         };
 
         crate::db::schema::file::Entity::insert(file_values)
@@ -310,59 +430,109 @@ impl AppState {
         Ok(u)
     }
 
-    pub async fn delete_fid(&self, user: &User, fid: FileID) -> Result<(), Error> {
-        info!("Deleting file: {fid}");
-        let db = self.db();
-        let file_entry = match self.get_file_db_entry(fid, db).await? {
-            Some(f) => f,
-            None => return Err(Error::FileNotFound),
-        };
+    /// Count a download of `fid`
+    ///
+    /// Returns how many downloads are left after this one, `None` if the file has no limit. If
+    /// it returns `Some(0)`, the file should be removed once the download is done. If no
+    /// downloads were left, the file is removed right away and this fails with
+    /// [`Error::FileNotFound`].
+    pub async fn register_download(&self, fid: FileID) -> Result<Option<u32>, Error> {
+        // a single conditional update, so that concurrent downloads can't go over the limit
+        let res = FileE::update_many()
+            .col_expr(FileC::DownloadCount, Expr::col(FileC::DownloadCount).add(1))
+            .filter(FileC::Id.eq(fid.inner()))
+            .filter(
+                Condition::any()
+                    .add(FileC::MaxDownloads.is_null())
+                    .add(Expr::col(FileC::DownloadCount).lt(Expr::col(FileC::MaxDownloads))),
+            )
+            .exec(self.db())
+            .await?;
 
-        let user_id = match file_entry.user_id {
-            Some(uid) => uid,
-            None => return Err(Error::Unauthorized),
-        };
-        let file_uploader = User::get_by_id(user_id, db).await?;
+        let file = self
+            .get_file_db_entry(fid, self.db())
+            .await?
+            .ok_or(Error::FileNotFound)?;
+        if res.rows_affected == 0 {
+            warn!("File {fid} has no downloads left");
+            self.remove_file(file).await?;
+            return Err(Error::FileNotFound);
+        }
 
-        if file_uploader == *user {
-            let file_name = self.get_filename_for_fid(fid)?;
-            let file_path = self.upload_datafile_for_fid(fid, &file_name, false)?;
-            let file_dir = file_path
-                .parent()
-                .expect("file path parent dir did not exist");
-            debug!("Path of file to be deleted: {}", file_dir.display());
+        Ok(file
+            .max_downloads
+            .map(|max| max.saturating_sub(file.download_count).max(0) as u32))
+    }
 
-            std::fs::remove_dir_all(file_dir)?;
-            file_entry.delete(db).await?;
+    /// Remove the data and the DB entry of a file, without any checks
+    ///
+    /// The blob of the file is only deleted if no other file uses it. Returns how many bytes
+    /// were freed, nothing if the file was already removed by someone else.
+    pub async fn remove_file(&self, file: FileM) -> Result<u64, Error> {
+        let fid: FileID = file.id.into();
+        debug!("Removing file {fid}");
+        // only whoever actually deletes the DB entry releases the blob, in the same transaction
+        let _guard = self.blob_lock.lock().await;
+        let txn = self.db().begin().await?;
+        if FileE::delete_by_id(file.id).exec(&txn).await?.rows_affected != 1 {
+            debug!("File {fid} was already removed");
+            return Ok(0);
+        }
+        let freed = match &file.blob {
+            Some(sha256) => self.release_blob(sha256, &txn).await?,
+            None => Some(file.size as u64),
+        };
+        txn.commit().await?;
 
-            Ok(())
-        } else {
-            Err(Error::Unauthorized)
+        self.storage().delete_all(&storage::key_fid(fid)).await?;
+        if let Some(sha256) = &file.blob
+            && freed.is_some()
+        {
+            self.storage().delete(&storage::key_blob(sha256)).await?;
         }
+        Ok(freed.unwrap_or(0))
+    }
+
+    /// The DB entry of the file `fid`, if it was uploaded by `user`
+    pub async fn get_owned_file(&self, user: &User, fid: FileID) -> Result<FileM, Error> {
+        let file = self
+            .get_file_db_entry(fid, self.db())
+            .await?
+            .ok_or(Error::FileNotFound)?;
+        if file.user_id != Some(user.id()) {
+            return Err(Error::Unauthorized);
+        }
+        Ok(file)
+    }
+
+    pub async fn delete_fid(&self, user: &User, fid: FileID) -> Result<(), Error> {
+        info!("Deleting file: {fid}");
+        let file_entry = self.get_owned_file(user, fid).await?;
+        self.remove_file(file_entry).await?;
+        Ok(())
     }
 }
 
 pub(crate) mod validators {
     use super::*;
     impl AppState {
-        pub(crate) fn validate_make_testfile(&self) -> Result<(), Error> {
+        pub(crate) async fn validate_make_testfile(&self) -> Result<(), Error> {
             debug!("validate_make_testfile");
             const TESTDATA: &[u8] = &[19, 13, 124, 25, 16, 2, 16, 37, 38, 84, 38, 92, 125, 15];
+            const TESTFILE_KEY: &str = "___testfile";
 
-            let mut testfile_path = self.storage_dir();
-            testfile_path.push("___testfile");
-            let mut testfile = std::fs::File::create(&testfile_path)?;
-            testfile.write_all(TESTDATA)?;
-            testfile.sync_all()?;
+            self.storage()
+                .put_bytes(TESTFILE_KEY, bytes::Bytes::from_static(TESTDATA))
+                .await?;
 
-            let content = std::fs::read(&testfile_path)?;
+            let content = self.storage().get(TESTFILE_KEY, None).await?;
             if TESTDATA != content {
                 panic!(
                     "validate_make_testfile: content written to the testfile does not match the constant TESTDATA"
                 )
             }
 
-            std::fs::remove_file(&testfile_path)?;
+            self.storage().delete(TESTFILE_KEY).await?;
             Ok(())
         }
 
@@ -377,12 +547,149 @@ pub(crate) mod validators {
             Ok(())
         }
 
+        /// Files uploaded before their size, creation time, name and content type were tracked
+        /// are missing those in the DB, take them from the storage
+        pub(crate) async fn validate_file_metadata(&self) -> Result<(), Error> {
+            debug!("validate_file_metadata");
+            for file in self.files().await? {
+                let needs_data =
+                    file.size == 0 || file.creation_time.is_none() || file.sha256.is_none();
+                let needs_content_type = file.content_type.is_none();
+                if !needs_data && !needs_content_type && file.name.is_some() {
+                    continue;
+                }
+                let fid: FileID = file.id.into();
+                let name = match self.get_filename_for_fid(fid).await {
+                    Ok(name) => name,
+                    Err(e) => {
+                        warn!("Could not find data of file {fid} to get its metadata: {e}");
+                        continue;
+                    }
+                };
+                let mut active: schema::file::ActiveModel = file.into();
+                active.name = sea_orm::ActiveValue::Set(Some(name.clone()));
+                if needs_content_type {
+                    let detected: Result<_, Error> = async {
+                        let data = self.stored_data(fid, &name).await?;
+                        let head = self
+                            .read_data(&data, Some(0..CONTENT_TYPE_DETECTION_LENGTH))
+                            .await?;
+                        detect_content_type(&head)
+                    }
+                    .await;
+                    match detected {
+                        Ok(content_type) => {
+                            active.content_type =
+                                sea_orm::ActiveValue::Set(Some(content_type.to_string()))
+                        }
+                        Err(e) => {
+                            warn!(
+                                "Could not read the data of file {fid} to get its content type: {e}"
+                            )
+                        }
+                    }
+                }
+                if needs_data {
+                    let read: Result<_, Error> = async {
+                        let key = self.stored_data(fid, &name).await?.key;
+                        Ok((
+                            self.storage().stat(&key).await?,
+                            self.hash_stored(&key).await?,
+                        ))
+                    }
+                    .await;
+                    // the scrub finds files whose data is missing
+                    let (meta, hashes) = match read {
+                        Ok(read) => read,
+                        Err(e) => {
+                            warn!("Could not read the data of file {fid} to get its metadata: {e}");
+                            continue;
+                        }
+                    };
+                    debug!("Filling in metadata of file {fid}: {meta:?}, {hashes:?}");
+                    active.size = sea_orm::ActiveValue::Set(meta.size as i64);
+                    active.creation_time = sea_orm::ActiveValue::Set(Some(meta.time_created));
+                    active.sha256 = sea_orm::ActiveValue::Set(Some(hashes.sha256));
+                    active.blake3 = sea_orm::ActiveValue::Set(hashes.blake3);
+                }
+                active.update(self.db()).await?;
+            }
+            Ok(())
+        }
+
         pub(crate) fn validate_config_base_url(&self) -> Result<(), Error> {
-            todo!()
+            debug!("validate_config_base_url");
+            let base_url = &self.config().service.base_url;
+            match actix_web::http::Uri::from_str(base_url) {
+                Ok(uri) if uri.scheme().is_some() && uri.authority().is_some() => Ok(()),
+                _ => Err(ConfigError::BadBaseUrl(base_url.to_string()).into()),
+            }
+        }
+
+        /// Remove what uploads that were interrupted by a crash left in the storage
+        ///
+        /// These are staged uploads that were not committed, and blobs that were stored for a
+        /// file that was not committed. Only objects older than the grace period of the scrub
+        /// are removed, as other servers on the same storage, or this one before it restarted,
+        /// may still be uploading.
+        pub(crate) async fn rollback_interrupted_uploads(&self) -> Result<(), Error> {
+            debug!("rollback_interrupted_uploads");
+            let cutoff = chrono::Utc::now().naive_utc() - self.config().files.scrub.grace_period.0;
+            for key in self.storage().list(storage::STAGING_PREFIX).await? {
+                if self.storage().stat(&key).await?.time_modified < cutoff {
+                    warn!("Removing interrupted upload {key}");
+                    self.storage().delete(&key).await?;
+                }
+            }
+            let blobs: std::collections::HashSet<String> = BlobE::find()
+                .all(self.db())
+                .await?
+                .into_iter()
+                .map(|blob| storage::key_blob(&blob.sha256))
+                .collect();
+            for key in self.storage().list(&storage::key_blob("")).await? {
+                if !blobs.contains(&key) && self.storage().stat(&key).await?.time_modified < cutoff
+                {
+                    warn!("Removing blob {key} of an interrupted upload");
+                    self.storage().delete(&key).await?;
+                }
+            }
+            Ok(())
+        }
+
+        pub(crate) fn validate_config_encryption(&self) -> Result<(), Error> {
+            debug!("validate_config_encryption");
+            let config = &self.config().files.encryption;
+            if config.enabled && config.master_key.is_none() {
+                return Err(ConfigError::MissingMasterKey.into());
+            }
+            Ok(())
+        }
+
+        pub(crate) fn validate_config_id_length(&self) -> Result<(), Error> {
+            debug!("validate_config_id_length");
+            let length = self.config().files.id_length;
+            if !FID_LENGTHS.contains(&length) {
+                return Err(ConfigError::BadIdLength { length }.into());
+            }
+            Ok(())
         }
     }
 }
 
+/// Detect the content type of data that starts with `head` with libmagic
+pub(crate) fn detect_content_type(head: &[u8]) -> Result<mime::Mime, Error> {
+    let flags = magic::cookie::Flags::MIME_TYPE | magic::cookie::Flags::MIME_ENCODING;
+    let cookie = magic::Cookie::open(flags)?;
+    let cookie = cookie
+        .load(&magic::cookie::DatabasePaths::default())
+        .expect("could not load database for libmagic file type detection");
+    Ok(
+        mime::Mime::from_str(&cookie.buffer(head).unwrap_or("unknown".to_string()))
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM),
+    )
+}
+
 pub fn load_config(config_file_path: impl Into<PathBuf>) -> Result<Config, Error> {
     let config_file_path: PathBuf = config_file_path.into();
     let config = Config::load(&config_file_path)?;
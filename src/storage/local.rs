@@ -0,0 +1,195 @@
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{StreamExt, TryStreamExt};
+use log::{debug, warn};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use super::{ByteStream, ObjectMeta, StorageBackend};
+use crate::errors::Error;
+
+/// Stores everything in a directory on the local filesystem, `files.storage_dir`
+pub struct LocalFs {
+    root: PathBuf,
+}
+
+impl LocalFs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path_for(&self, key: &str) -> Result<PathBuf, Error> {
+        let relative = Path::new(key);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            warn!("Refusing to use bad storage key: {key}");
+            return Err(Error::BadStorageKey(key.to_string()));
+        }
+        Ok(self.root.join(relative))
+    }
+
+    async fn create_parent(path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Ok(())
+    }
+
+    async fn open_range(
+        &self,
+        key: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<tokio::io::Take<tokio::fs::File>, Error> {
+        let mut file = tokio::fs::File::open(self.path_for(key)?).await?;
+        let range = range.unwrap_or(0..u64::MAX);
+        file.seek(SeekFrom::Start(range.start)).await?;
+        Ok(file.take(range.end.saturating_sub(range.start)))
+    }
+
+    fn walk(dir: &Path, prefix: &str, keys: &mut Vec<String>) -> Result<(), Error> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let key = format!("{prefix}{}", entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                Self::walk(&entry.path(), &format!("{key}/"), keys)?;
+            } else {
+                keys.push(key);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalFs {
+    async fn validate(&self) -> Result<(), Error> {
+        if !self.root.exists() {
+            std::fs::create_dir_all(&self.root)?;
+        }
+        if !self.root.is_dir() {
+            return Err(Error::StorageDirNotADir);
+        }
+        Ok(())
+    }
+
+    async fn put(&self, key: &str, source: &Path) -> Result<(), Error> {
+        let path = self.path_for(key)?;
+        Self::create_parent(&path).await?;
+        debug!("Moving {} to {}", source.display(), path.display());
+        match tokio::fs::rename(source, &path).await {
+            Ok(()) => Ok(()),
+            // the temporary file might be on a different filesystem
+            Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+                tokio::fs::copy(source, &path).await?;
+                tokio::fs::remove_file(source).await?;
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn put_bytes(&self, key: &str, data: Bytes) -> Result<(), Error> {
+        let path = self.path_for(key)?;
+        Self::create_parent(&path).await?;
+        tokio::fs::write(&path, data).await?;
+        Ok(())
+    }
+
+    async fn put_stream(&self, key: &str, mut data: ByteStream) -> Result<(), Error> {
+        let path = self.path_for(key)?;
+        Self::create_parent(&path).await?;
+        let mut file = tokio::fs::File::create(&path).await?;
+        let written: Result<(), Error> = async {
+            while let Some(chunk) = data.try_next().await? {
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            Ok(())
+        }
+        .await;
+        if written.is_err() {
+            drop(file);
+            tokio::fs::remove_file(&path).await?;
+        }
+        written
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Bytes, Error> {
+        let mut buf = Vec::new();
+        self.open_range(key, range)
+            .await?
+            .read_to_end(&mut buf)
+            .await?;
+        Ok(buf.into())
+    }
+
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> Result<ByteStream, Error> {
+        let reader = self.open_range(key, range).await?;
+        Ok(tokio_util::io::ReaderStream::new(reader)
+            .map_err(Error::from)
+            .boxed())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        let to = self.path_for(to)?;
+        Self::create_parent(&to).await?;
+        tokio::fs::rename(self.path_for(from)?, &to).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        tokio::fs::remove_file(self.path_for(key)?).await?;
+        Ok(())
+    }
+
+    async fn delete_all(&self, prefix: &str) -> Result<(), Error> {
+        let path = self.path_for(prefix)?;
+        if path.is_dir() {
+            tokio::fs::remove_dir_all(&path).await?;
+        } else if path.exists() {
+            tokio::fs::remove_file(&path).await?;
+        }
+        Ok(())
+    }
+
+    async fn stat(&self, key: &str) -> Result<ObjectMeta, Error> {
+        let fsmeta = tokio::fs::metadata(self.path_for(key)?).await?;
+        Ok(ObjectMeta {
+            size: fsmeta.size(),
+            time_created: systime_to_chrono(fsmeta.created()?),
+            time_modified: systime_to_chrono(fsmeta.modified()?),
+            time_accessed: systime_to_chrono(fsmeta.accessed()?),
+        })
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let dir = if prefix.is_empty() {
+            self.root.clone()
+        } else {
+            self.path_for(prefix)?
+        };
+        let mut keys = Vec::new();
+        if !dir.is_dir() {
+            return Ok(keys);
+        }
+        let prefix = if prefix.is_empty() || prefix.ends_with('/') {
+            prefix.to_string()
+        } else {
+            format!("{prefix}/")
+        };
+        Self::walk(&dir, &prefix, &mut keys)?;
+        Ok(keys)
+    }
+}
+
+fn systime_to_chrono(t: SystemTime) -> chrono::NaiveDateTime {
+    let datetime: chrono::DateTime<chrono::Utc> = chrono::DateTime::from(t);
+    datetime.naive_utc()
+}
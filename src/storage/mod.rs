@@ -0,0 +1,177 @@
+//! Abstraction over the place where uploaded data is actually kept.
+//!
+//! Everything that touches stored file data goes through a [`StorageBackend`]. Objects are
+//! addressed with keys, which are `/` separated paths relative to the root of the storage,
+//! for example `1337/data/foo.txt`. Use the `key_*` functions of this module to build them
+//! instead of formatting them by hand.
+
+use std::ops::Range;
+use std::path::Path;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::NaiveDateTime;
+use futures_util::stream::BoxStream;
+
+use crate::config::{FilesConfig, StorageBackendKind};
+use crate::errors::{ConfigError, Error};
+use crate::files::FileID;
+
+mod local;
+mod object;
+mod presign;
+
+pub use self::local::LocalFs;
+pub use self::object::ObjectStorage;
+
+/// Stream of chunks of a stored object
+pub type ByteStream = BoxStream<'static, Result<Bytes, Error>>;
+
+/// Metadata of a single stored object
+#[derive(Debug, Clone)]
+pub struct ObjectMeta {
+    pub size: u64,
+    pub time_created: NaiveDateTime,
+    pub time_modified: NaiveDateTime,
+    pub time_accessed: NaiveDateTime,
+}
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Check that the storage is reachable, creating it if needed
+    async fn validate(&self) -> Result<(), Error>;
+
+    /// Move the local file at `source` into the storage, replacing anything stored at `key`
+    async fn put(&self, key: &str, source: &Path) -> Result<(), Error>;
+
+    /// Store `data` at `key`, replacing anything stored there
+    async fn put_bytes(&self, key: &str, data: Bytes) -> Result<(), Error>;
+
+    /// Store everything from `data` at `key`, replacing anything stored there
+    ///
+    /// If `data` yields an error, nothing is left at `key` and the error is returned.
+    async fn put_stream(&self, key: &str, data: ByteStream) -> Result<(), Error>;
+
+    /// Read the object at `key` into memory
+    ///
+    /// If `range` is given, only that part of the object is read. A range that reaches over
+    /// the end of the object is cut off.
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Bytes, Error>;
+
+    /// Like [`get`](Self::get), but without holding the whole object in memory
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> Result<ByteStream, Error>;
+
+    /// Move the object at `from` to `to`, replacing anything stored there
+    async fn rename(&self, from: &str, to: &str) -> Result<(), Error>;
+
+    /// Remove the object at `key`
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+
+    /// Remove every object whose key starts with `prefix`
+    async fn delete_all(&self, prefix: &str) -> Result<(), Error>;
+
+    async fn stat(&self, key: &str) -> Result<ObjectMeta, Error>;
+
+    /// Keys of all objects below `prefix`, recursively
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error>;
+
+    /// A temporary url from which the object at `key` can be downloaded directly, as a file
+    /// named `filename` of `content_type`
+    ///
+    /// Returns `None` if the backend can't do this or is not configured to, in which case the
+    /// object needs to be streamed.
+    async fn presigned_url(
+        &self,
+        _key: &str,
+        _filename: &str,
+        _content_type: &str,
+    ) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+}
+
+pub fn from_config(config: &FilesConfig) -> Result<Box<dyn StorageBackend>, Error> {
+    Ok(match config.storage_backend {
+        StorageBackendKind::Local => Box::new(LocalFs::new(&config.storage_dir)),
+        StorageBackendKind::S3 => match &config.s3 {
+            Some(s3_config) => Box::new(ObjectStorage::s3(s3_config)?),
+            None => return Err(ConfigError::MissingSection("files.s3".to_string()).into()),
+        },
+    })
+}
+
+/// Prefix of [`key_staging`]
+pub const STAGING_PREFIX: &str = "staging/";
+
+/// Prefix under which everything belonging to `fid` is stored
+pub fn key_fid(fid: FileID) -> String {
+    format!("{}/", fid.inner())
+}
+
+/// Prefix under which the data file of `fid` is stored
+pub fn key_fid_data(fid: FileID) -> String {
+    format!("{}data/", key_fid(fid))
+}
+
+/// Key of the data file of `fid`
+pub fn key_fid_datafile(fid: FileID, name: &str) -> String {
+    format!("{}{name}", key_fid_data(fid))
+}
+
+/// Prefix under which the thumbnails of `fid` are stored
+pub fn key_fid_thumbnails(fid: FileID) -> String {
+    format!("{}thumbnails/", key_fid(fid))
+}
+
+/// Key of the thumbnail of `fid` in the size `size`
+pub fn key_fid_thumbnail(fid: FileID, size: &str) -> String {
+    format!("{}{size}", key_fid_thumbnails(fid))
+}
+
+/// Prefix under which an upload to `fid` is kept until it is committed
+///
+/// Anything below it is left over from an interrupted upload when the server starts.
+pub fn key_staging(fid: FileID) -> String {
+    format!("{STAGING_PREFIX}{}/", fid.inner())
+}
+
+/// Key of the data of an upload to `fid` that is not committed yet
+pub fn key_staged_upload(fid: FileID) -> String {
+    format!("{}upload", key_staging(fid))
+}
+
+/// Key under which a new blob `sha256` is stored before it is moved to its [`key_blob`]
+///
+/// `nonce` keeps concurrent uploads of the same data apart.
+pub fn key_staged_blob(sha256: &str, nonce: &str) -> String {
+    format!("{STAGING_PREFIX}blobs/{sha256}.{nonce}")
+}
+
+/// Key of the blob with the hex encoded SHA-256 `sha256`
+///
+/// The data of files is stored in blobs, so that files with the same content share one.
+pub fn key_blob(sha256: &str) -> String {
+    format!("blobs/{sha256}")
+}
+
+/// The last segment of `key`, the name of the object
+pub fn key_basename(key: &str) -> &str {
+    key.rsplit('/').next().unwrap_or(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let fid = FileID::from(1337);
+        assert_eq!(key_fid(fid), "1337/");
+        assert_eq!(key_fid_data(fid), "1337/data/");
+        assert_eq!(key_fid_datafile(fid, "foo.txt"), "1337/data/foo.txt");
+        assert_eq!(key_basename(&key_fid_datafile(fid, "foo.txt")), "foo.txt");
+        assert_eq!(key_fid_thumbnail(fid, "medium"), "1337/thumbnails/medium");
+        assert_eq!(key_staged_upload(fid), "staging/1337/upload");
+        assert_eq!(key_blob("abcd"), "blobs/abcd");
+    }
+}
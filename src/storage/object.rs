@@ -0,0 +1,283 @@
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{StreamExt, TryStreamExt};
+use log::{debug, info};
+use object_store::aws::AmazonS3Builder;
+use object_store::buffered::BufWriter;
+use object_store::path::Path as ObjectPath;
+use object_store::{GetOptions, GetRange, ObjectStore, PutPayload};
+use tokio::io::AsyncWriteExt;
+
+use super::presign::Presigner;
+use super::{ByteStream, ObjectMeta, StorageBackend};
+use crate::config::S3Config;
+use crate::errors::Error;
+
+/// Stores everything in an object store, like an S3 compatible bucket
+pub struct ObjectStorage {
+    store: Arc<dyn ObjectStore>,
+    /// Only set if downloads should be redirected to presigned urls
+    presigner: Option<Presigner>,
+    prefix: String,
+}
+
+impl ObjectStorage {
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: &str) -> Self {
+        Self {
+            store,
+            presigner: None,
+            prefix: prefix.trim_matches('/').to_string(),
+        }
+    }
+
+    pub fn s3(config: &S3Config) -> Result<Self, Error> {
+        let mut builder = AmazonS3Builder::new()
+            .with_bucket_name(&config.bucket)
+            .with_region(&config.region)
+            .with_access_key_id(&config.access_key_id)
+            .with_secret_access_key(&config.secret_access_key)
+            .with_allow_http(config.allow_http);
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        let s3 = Arc::new(builder.build()?);
+        info!("Using S3 bucket '{}' for file storage", config.bucket);
+
+        let mut storage = Self::new(s3, &config.prefix);
+        if config.presigned_downloads {
+            storage.presigner = Some(Presigner::new(config)?);
+        }
+        Ok(storage)
+    }
+
+    fn path_for(&self, key: &str) -> Result<ObjectPath, Error> {
+        let key = key.trim_end_matches('/');
+        let full = match (self.prefix.is_empty(), key.is_empty()) {
+            (true, _) => key.to_string(),
+            (false, true) => self.prefix.clone(),
+            (false, false) => format!("{}/{key}", self.prefix),
+        };
+        ObjectPath::parse(full).map_err(|_| Error::BadStorageKey(key.to_string()))
+    }
+
+    /// Reverse of [`path_for`](Self::path_for)
+    fn key_for(&self, path: &ObjectPath) -> String {
+        let path = path.as_ref();
+        match path.strip_prefix(&self.prefix) {
+            Some(key) if !self.prefix.is_empty() => key.trim_start_matches('/').to_string(),
+            _ => path.to_string(),
+        }
+    }
+
+    /// Cut `range` off at the end of the object, `None` if nothing is left
+    async fn clamp_range(
+        &self,
+        path: &ObjectPath,
+        range: Range<u64>,
+    ) -> Result<Option<Range<u64>>, Error> {
+        let size = self.store.head(path).await?.size;
+        let range = range.start.min(size)..range.end.min(size);
+        Ok((!range.is_empty()).then_some(range))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ObjectStorage {
+    async fn validate(&self) -> Result<(), Error> {
+        self.store.list_with_delimiter(None).await?;
+        Ok(())
+    }
+
+    async fn put(&self, key: &str, source: &Path) -> Result<(), Error> {
+        let path = self.path_for(key)?;
+        debug!("Uploading {} to {path}", source.display());
+        let mut file = tokio::fs::File::open(source).await?;
+        let mut writer = BufWriter::new(self.store.clone(), path);
+        tokio::io::copy(&mut file, &mut writer).await?;
+        writer.shutdown().await?;
+        tokio::fs::remove_file(source).await?;
+        Ok(())
+    }
+
+    async fn put_bytes(&self, key: &str, data: Bytes) -> Result<(), Error> {
+        self.store
+            .put(&self.path_for(key)?, PutPayload::from_bytes(data))
+            .await?;
+        Ok(())
+    }
+
+    async fn put_stream(&self, key: &str, mut data: ByteStream) -> Result<(), Error> {
+        let mut writer = BufWriter::new(self.store.clone(), self.path_for(key)?);
+        let written: Result<(), Error> = async {
+            while let Some(chunk) = data.try_next().await? {
+                writer.write_all(&chunk).await?;
+            }
+            Ok(())
+        }
+        .await;
+        match written {
+            Ok(()) => Ok(writer.shutdown().await?),
+            Err(e) => {
+                writer.abort().await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Bytes, Error> {
+        let path = self.path_for(key)?;
+        match range {
+            None => Ok(self.store.get(&path).await?.bytes().await?),
+            Some(range) => match self.clamp_range(&path, range).await? {
+                Some(range) => Ok(self.store.get_range(&path, range).await?),
+                None => Ok(Bytes::new()),
+            },
+        }
+    }
+
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> Result<ByteStream, Error> {
+        let path = self.path_for(key)?;
+        let range = match range {
+            None => None,
+            Some(range) => match self.clamp_range(&path, range).await? {
+                Some(range) => Some(GetRange::Bounded(range)),
+                None => return Ok(futures_util::stream::empty().boxed()),
+            },
+        };
+        let options = GetOptions {
+            range,
+            ..Default::default()
+        };
+        Ok(self
+            .store
+            .get_opts(&path, options)
+            .await?
+            .into_stream()
+            .map_err(Error::from)
+            .boxed())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        self.store
+            .rename(&self.path_for(from)?, &self.path_for(to)?)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.store.delete(&self.path_for(key)?).await?;
+        Ok(())
+    }
+
+    async fn delete_all(&self, prefix: &str) -> Result<(), Error> {
+        let locations = self
+            .store
+            .list(Some(&self.path_for(prefix)?))
+            .map_ok(|meta| meta.location)
+            .boxed();
+        self.store
+            .delete_stream(locations)
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(())
+    }
+
+    async fn stat(&self, key: &str) -> Result<ObjectMeta, Error> {
+        let meta = self.store.head(&self.path_for(key)?).await?;
+        let modified = meta.last_modified.naive_utc();
+        Ok(ObjectMeta {
+            size: meta.size,
+            // object stores only know when an object was last written
+            time_created: modified,
+            time_modified: modified,
+            time_accessed: modified,
+        })
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let prefix = self.path_for(prefix)?;
+        let prefix = (!prefix.as_ref().is_empty()).then_some(prefix);
+        Ok(self
+            .store
+            .list(prefix.as_ref())
+            .map_ok(|meta| self.key_for(&meta.location))
+            .try_collect()
+            .await?)
+    }
+
+    async fn presigned_url(
+        &self,
+        key: &str,
+        filename: &str,
+        content_type: &str,
+    ) -> Result<Option<String>, Error> {
+        let Some(presigner) = &self.presigner else {
+            return Ok(None);
+        };
+        let path = self.path_for(key)?;
+        Ok(Some(presigner.url(path.as_ref(), filename, content_type)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_object_storage_roundtrip() {
+        let storage = ObjectStorage::new(Arc::new(InMemory::new()), "tempfiles/");
+
+        storage
+            .put_bytes("1337/data/foo.txt", Bytes::from_static(b"hello world"))
+            .await
+            .unwrap();
+        storage
+            .put_bytes("42/data/bar.txt", Bytes::from_static(b"bar"))
+            .await
+            .unwrap();
+
+        assert_eq!(storage.stat("1337/data/foo.txt").await.unwrap().size, 11);
+        assert_eq!(
+            storage
+                .get("1337/data/foo.txt", Some(6..100))
+                .await
+                .unwrap(),
+            Bytes::from_static(b"world")
+        );
+        assert!(
+            storage
+                .get("1337/data/foo.txt", Some(50..100))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            storage.list("1337/").await.unwrap(),
+            vec!["1337/data/foo.txt".to_string()]
+        );
+
+        storage
+            .rename("1337/data/foo.txt", "blobs/foo")
+            .await
+            .unwrap();
+        assert!(storage.list("1337/").await.unwrap().is_empty());
+        assert_eq!(storage.stat("blobs/foo").await.unwrap().size, 11);
+
+        storage.delete_all("blobs/").await.unwrap();
+        assert!(storage.list("blobs/").await.unwrap().is_empty());
+        assert_eq!(storage.list("").await.unwrap().len(), 1);
+        assert!(
+            storage
+                .presigned_url("42/data/bar.txt", "bar.txt", "text/plain")
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
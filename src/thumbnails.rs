@@ -0,0 +1,285 @@
+//! Smaller versions of uploaded images, so that previews don't have to load the original
+//!
+//! After a file is committed, its id is queued for the [`thumbnail_worker`]. If the file is an
+//! image that the server can decode, the worker stores one image for each [`ThumbnailSize`]
+//! below the id of the file in the storage, so that they are removed together with it. Files
+//! that were uploaded before, or whose thumbnails were lost because the queue was full, are
+//! queued again when their thumbnail is requested.
+//!
+//! Files that are encrypted by the uploader or have a download limit get no thumbnails, as
+//! they would show the content without a download. With encryption at rest, the thumbnails are
+//! encrypted with a key [derived](crate::encryption::DataKey::derive) from the key of the blob.
+
+use std::io::Cursor;
+
+use actix_web::web;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::compression::StoredData;
+use crate::encryption;
+use crate::errors::Error;
+use crate::files::{FileID, FileInfos};
+use crate::state::AppState;
+use crate::storage;
+
+/// How many files may wait for their thumbnails, further files are skipped until their
+/// thumbnail is requested
+pub const THUMBNAIL_QUEUE_LENGTH: usize = 256;
+/// Larger images are not read into memory to make thumbnails
+const MAX_SOURCE_SIZE: u64 = 32 * 1024 * 1024;
+/// Larger images are not decoded
+const MAX_SOURCE_DIMENSION: u32 = 16384;
+/// How much memory decoding an image may take
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+/// Subtypes of the `image` content types that can be decoded
+const SUPPORTED_SUBTYPES: [&str; 4] = ["jpeg", "png", "gif", "webp"];
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailSize {
+    /// For lists of files
+    #[default]
+    Thumbnail,
+    /// For the preview of a single file
+    Medium,
+}
+
+impl ThumbnailSize {
+    pub const ALL: [Self; 2] = [Self::Thumbnail, Self::Medium];
+
+    /// Name in urls and the storage
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Thumbnail => "thumbnail",
+            Self::Medium => "medium",
+        }
+    }
+
+    /// The longer side of the image is at most this many pixels
+    pub fn max_dimension(&self) -> u32 {
+        match self {
+            Self::Thumbnail => 256,
+            Self::Medium => 1024,
+        }
+    }
+}
+
+impl FileInfos {
+    /// Does this file get thumbnails?
+    pub fn has_thumbnails(&self) -> bool {
+        let Ok(content_type) = self.content_type() else {
+            return false;
+        };
+        content_type.type_() == mime::IMAGE
+            && SUPPORTED_SUBTYPES.contains(&content_type.subtype().as_str())
+            && self.size <= MAX_SOURCE_SIZE
+            && !self.encrypted
+            && self.downloads_remaining.is_none()
+    }
+}
+
+impl AppState {
+    /// Make the thumbnails of `fid` in the background
+    pub fn queue_thumbnails(&self, fid: FileID) {
+        match self.thumbnail_queue.try_send(fid) {
+            Ok(()) => (),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                debug!("The thumbnail queue is full, skipping {fid}")
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                warn!("Nothing makes thumbnails, skipping {fid}")
+            }
+        }
+    }
+
+    /// Make the missing thumbnails of `fid`, if it gets any
+    pub async fn make_thumbnails(&self, fid: FileID) -> Result<(), Error> {
+        let name = self.get_filename_for_fid(fid).await?;
+        if !self.make_file_infos(fid, &name).await?.has_thumbnails() {
+            return Ok(());
+        }
+        let existing = self
+            .storage()
+            .list(&storage::key_fid_thumbnails(fid))
+            .await?;
+        let missing: Vec<ThumbnailSize> = ThumbnailSize::ALL
+            .into_iter()
+            .filter(|size| !existing.contains(&storage::key_fid_thumbnail(fid, size.name())))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let data = self.stored_data(fid, &name).await?;
+        let source = self.read_data(&data, None).await?;
+        let thumbnails = tokio::task::spawn_blocking(move || make_thumbnails(&source, &missing))
+            .await
+            .map_err(std::io::Error::other)??;
+        for (size, thumbnail) in thumbnails {
+            let key = storage::key_fid_thumbnail(fid, size.name());
+            let mut stream = futures_util::stream::once(async { Ok(thumbnail) }).boxed();
+            if let Some(data_key) = thumbnail_key(&data, fid, size) {
+                stream = encryption::encrypt(stream, data_key);
+            }
+            self.storage().put_stream(&key, stream).await?;
+        }
+        // the file may have been removed in the meantime
+        if self.get_file_db_entry(fid, self.db()).await?.is_none() {
+            self.storage()
+                .delete_all(&storage::key_fid_thumbnails(fid))
+                .await?;
+            return Ok(());
+        }
+        info!("Made thumbnails for {fid}");
+        Ok(())
+    }
+
+    /// The thumbnail of the file `fid` named `name` in `size`, `None` if it was not made yet
+    pub async fn thumbnail(
+        &self,
+        fid: FileID,
+        name: &str,
+        size: ThumbnailSize,
+    ) -> Result<Option<Bytes>, Error> {
+        let data = self.stored_data(fid, name).await?;
+        let key = storage::key_fid_thumbnail(fid, size.name());
+        if !self
+            .storage()
+            .list(&storage::key_fid_thumbnails(fid))
+            .await?
+            .contains(&key)
+        {
+            return Ok(None);
+        }
+        let stored_size = self.storage().stat(&key).await?.size;
+        let data_key = thumbnail_key(&data, fid, size);
+        let thumbnail = StoredData {
+            key,
+            size: match data_key {
+                Some(_) => encryption::decrypted_size(stored_size),
+                None => stored_size,
+            },
+            compression: None,
+            data_key,
+        };
+        Ok(Some(self.read_data(&thumbnail, None).await?))
+    }
+}
+
+/// Make the thumbnails of `fid` for files that are queued with
+/// [`queue_thumbnails`](AppState::queue_thumbnails), one after the other
+pub async fn thumbnail_worker(state: web::Data<AppState>) {
+    let Some(mut queue) = state.thumbnail_jobs.lock().await.take() else {
+        warn!("The thumbnail worker is already running");
+        return;
+    };
+    while let Some(fid) = queue.recv().await {
+        if let Err(e) = state.make_thumbnails(fid).await {
+            warn!("Could not make thumbnails for {fid}: {e}");
+        }
+    }
+}
+
+/// The key of the thumbnail of `fid` in `size`, if the file `data` is encrypted at rest
+fn thumbnail_key(
+    data: &StoredData,
+    fid: FileID,
+    size: ThumbnailSize,
+) -> Option<encryption::DataKey> {
+    // several files can share a blob, and a blob its key
+    data.data_key
+        .as_ref()
+        .map(|key| key.derive(&format!("thumbnail {} {}", fid.inner(), size.name())))
+}
+
+/// Decode the image `source` and encode it again in each of `sizes`
+///
+/// Images with transparency become PNGs, all others JPEGs.
+fn make_thumbnails(
+    source: &[u8],
+    sizes: &[ThumbnailSize],
+) -> Result<Vec<(ThumbnailSize, Bytes)>, Error> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_DIMENSION);
+    limits.max_image_height = Some(MAX_SOURCE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    let mut reader = ImageReader::new(Cursor::new(source)).with_guessed_format()?;
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    let mut thumbnails = Vec::new();
+    for &size in sizes {
+        let max = size.max_dimension();
+        let resized = if image.width() > max || image.height() > max {
+            image.thumbnail(max, max)
+        } else {
+            image.clone()
+        };
+        let mut out = Cursor::new(Vec::new());
+        if resized.color().has_alpha() {
+            resized.write_to(&mut out, ImageFormat::Png)?;
+        } else {
+            let encoder =
+                image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY);
+            resized.to_rgb8().write_with_encoder(encoder)?;
+        }
+        thumbnails.push((size, out.into_inner().into()));
+    }
+    Ok(thumbnails)
+}
+
+/// The content type of a thumbnail
+pub fn thumbnail_content_type(thumbnail: &[u8]) -> &'static str {
+    match image::guess_format(thumbnail) {
+        Ok(format) => format.to_mime_type(),
+        Err(_) => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::{GenericImageView, Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn test_make_thumbnails() {
+        let mut source = Cursor::new(Vec::new());
+        RgbImage::from_pixel(2000, 500, Rgb([200, 10, 10]))
+            .write_to(&mut source, ImageFormat::Png)
+            .unwrap();
+        let thumbnails = make_thumbnails(source.get_ref(), &ThumbnailSize::ALL).unwrap();
+        assert_eq!(thumbnails.len(), 2);
+        for (size, thumbnail) in thumbnails {
+            assert_eq!(thumbnail_content_type(&thumbnail), "image/jpeg");
+            let image = image::load_from_memory(&thumbnail).unwrap();
+            assert_eq!(image.width(), size.max_dimension());
+            assert_eq!(image.height(), size.max_dimension() / 4);
+        }
+
+        // small images keep their size, transparency is kept
+        let mut source = Cursor::new(Vec::new());
+        RgbaImage::from_pixel(10, 20, Rgba([0, 0, 0, 0]))
+            .write_to(&mut source, ImageFormat::Png)
+            .unwrap();
+        let (_, thumbnail) = make_thumbnails(source.get_ref(), &[ThumbnailSize::Thumbnail])
+            .unwrap()
+            .remove(0);
+        assert_eq!(thumbnail_content_type(&thumbnail), "image/png");
+        assert_eq!(
+            image::load_from_memory(&thumbnail).unwrap().dimensions(),
+            (10, 20)
+        );
+
+        assert!(make_thumbnails(b"not an image", &ThumbnailSize::ALL).is_err());
+    }
+}
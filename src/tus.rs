@@ -0,0 +1,467 @@
+//! Resumable uploads with the [tus 1.0](https://tus.io/protocols/resumable-upload) protocol.
+//!
+//! Supported extensions are `creation`, `termination` and `expiration`. The data of an
+//! unfinished upload is kept in `files.staging_dir` and its metadata in the `partial_upload`
+//! table. Once all bytes are there, the upload is moved into the storage like any other file.
+//!
+//! The `Upload-Metadata` of an upload may contain `filename` and the options of a normal
+//! upload: `expires_in`, `max_downloads`, `password`, `sha256`, `encrypted` and `slug`. The
+//! response to the final `PATCH`, or to the `POST` of an empty upload, has the id and url of
+//! the new file in the `File-ID` and `File-Url` headers. If the finished upload does not have
+//! the expected `sha256`, it is discarded. If it can't be stored for another reason, it is
+//! kept and the final `PATCH` can be sent again.
+//!
+//! Only one request at a time can write to or terminate an upload, others fail with
+//! `409 Conflict` until it is done.
+
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use actix_web::http::StatusCode;
+use actix_web::http::header::{self, HttpDate};
+use actix_web::{
+    HttpRequest, HttpResponse, HttpResponseBuilder, Responder, delete, head, options, patch, post,
+    web,
+};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::NaiveDateTime;
+use futures_util::StreamExt;
+use log::{debug, info, warn};
+use rand::distr::SampleString;
+use sea_orm::{
+    ActiveModelTrait as _, ColumnTrait as _, EntityTrait as _, ModelTrait as _, QueryFilter as _,
+};
+use tokio::io::AsyncWriteExt;
+
+use crate::auth::MaybeAuthUser;
+use crate::blobs::BlobSource;
+use crate::db::schema::partial_upload::{
+    self, Column as PartialUploadC, Entity as PartialUploadE, Model as PartialUploadM,
+};
+use crate::errors::Error;
+use crate::file_edit;
+use crate::files::{DEFAULT_UPLOAD_NAME, FileInfos, UploadOptions};
+use crate::state::AppState;
+use crate::user::User;
+
+pub const TUS_VERSION: &str = "1.0.0";
+pub const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+const TUS_RESUMABLE_HEADER: &str = "Tus-Resumable";
+const UPLOAD_LENGTH_HEADER: &str = "Upload-Length";
+const UPLOAD_OFFSET_HEADER: &str = "Upload-Offset";
+const UPLOAD_METADATA_HEADER: &str = "Upload-Metadata";
+const UPLOAD_EXPIRES_HEADER: &str = "Upload-Expires";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+/// Id of the finished file, only in the response to the last `PATCH`
+pub const FILE_ID_HEADER: &str = "File-ID";
+/// Frontend url of the finished file, with its slug if it has one, only in the response to the
+/// last `PATCH`
+pub const FILE_URL_HEADER: &str = "File-Url";
+
+const UPLOAD_ID_LEN: usize = 32;
+
+#[options("/uploads")]
+pub async fn api_view_options_uploads(
+    state: web::Data<AppState>,
+    identity: MaybeAuthUser,
+) -> Result<impl Responder, Error> {
+    let max_size = state.max_upload_size(identity.user_ref())?;
+    Ok(tus_response(StatusCode::NO_CONTENT)
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", max_size.to_string()))
+        .finish())
+}
+
+#[post("/uploads")]
+pub async fn api_view_post_uploads(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    identity: MaybeAuthUser,
+) -> Result<impl Responder, Error> {
+    check_tus_version(&req)?;
+    let user = identity.user();
+    if user.is_none() && !state.config().accounts.allow_anon {
+        return Err(Error::Unauthorized);
+    }
+
+    let length: u64 = required_header(&req, UPLOAD_LENGTH_HEADER)?
+        .parse()
+        .map_err(|_| Error::BadHeader(UPLOAD_LENGTH_HEADER.to_string()))?;
+    let max_size = state.max_upload_size(user.as_ref())?;
+    if length > max_size {
+        warn!("Resumable upload is too large: {length}");
+        return Err(Error::UploadTooLarge(max_size));
+    }
+
+    let metadata = UploadMetadata::from_request(&req)?;
+    // fail early if the options are bad, they are applied when the upload is finished
+    let new_file = state.new_file(user.as_ref(), &metadata.options, length)?;
+    state
+        .check_storage_quota(user.as_ref(), length, new_file.expected_sha256.as_deref())
+        .await?;
+
+    let id = rand::distr::Alphanumeric.sample_string(&mut *state.csprng().await, UPLOAD_ID_LEN);
+    info!("Starting resumable upload {id} of {length} bytes");
+    tokio::fs::File::create(state.staging_path(&id)).await?;
+
+    let now = chrono::Utc::now().naive_utc();
+    let upload = partial_upload::ActiveModel {
+        id: sea_orm::ActiveValue::Set(id.clone()),
+        user_id: sea_orm::ActiveValue::Set(user.as_ref().map(|u| u.id())),
+        name: sea_orm::ActiveValue::Set(metadata.name),
+        length: sea_orm::ActiveValue::Set(length as i64),
+        expires_in: sea_orm::ActiveValue::Set(metadata.options.expires_in),
+        max_downloads: sea_orm::ActiveValue::Set(new_file.max_downloads.map(|n| n as i32)),
+        password_hash: sea_orm::ActiveValue::Set(new_file.password_hash),
+        sha256: sea_orm::ActiveValue::Set(new_file.expected_sha256),
+        encrypted: sea_orm::ActiveValue::Set(new_file.encrypted),
+        slug: sea_orm::ActiveValue::Set(new_file.slug),
+        creation_time: sea_orm::ActiveValue::Set(now),
+        expiration_time: sea_orm::ActiveValue::Set(state.partial_upload_expiration()),
+    }
+    .insert(state.db())
+    .await?;
+
+    let mut res = tus_response(StatusCode::CREATED);
+    res.insert_header((header::LOCATION, state.uri_api_upload_id(&id).to_string()));
+    if length == 0 {
+        // there is nothing to send with a PATCH, so the upload is already complete
+        let finfos = state.commit_partial_upload(upload).await?;
+        insert_file_headers(&mut res, finfos);
+    } else {
+        res.insert_header((UPLOAD_EXPIRES_HEADER, http_date(upload.expiration_time)));
+    }
+    Ok(res.finish())
+}
+
+#[head("/uploads/{id}")]
+pub async fn api_view_head_upload_id(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<impl Responder, Error> {
+    let upload = state.get_partial_upload(&path.into_inner()).await?;
+    let offset = state.partial_upload_offset(&upload).await?;
+    Ok(tus_response(StatusCode::OK)
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .insert_header((UPLOAD_OFFSET_HEADER, offset.to_string()))
+        .insert_header((UPLOAD_LENGTH_HEADER, upload.length.to_string()))
+        .insert_header((UPLOAD_EXPIRES_HEADER, http_date(upload.expiration_time)))
+        .finish())
+}
+
+#[patch("/uploads/{id}")]
+pub async fn api_view_patch_upload_id(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    mut payload: web::Payload,
+) -> Result<impl Responder, Error> {
+    check_tus_version(&req)?;
+    if required_header(&req, header::CONTENT_TYPE.as_str())? != OFFSET_CONTENT_TYPE {
+        return Err(Error::BadUploadContentType);
+    }
+    let _claim = state.claim_partial_upload(&path)?;
+    let upload = state.get_partial_upload(&path).await?;
+    let length = upload.length as u64;
+    let mut offset = state.partial_upload_offset(&upload).await?;
+    let requested_offset: u64 = required_header(&req, UPLOAD_OFFSET_HEADER)?
+        .parse()
+        .map_err(|_| Error::BadHeader(UPLOAD_OFFSET_HEADER.to_string()))?;
+    if requested_offset != offset {
+        return Err(Error::UploadOffsetMismatch(offset));
+    }
+
+    debug!("Continuing resumable upload {} at {offset}", upload.id);
+    let mut file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(state.staging_path(&upload.id))
+        .await?;
+    // keep everything that arrived, even if the connection breaks
+    let mut received = Ok(());
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                received = Err(Error::from(e));
+                break;
+            }
+        };
+        if offset + chunk.len() as u64 > length {
+            received = Err(Error::UploadTooLarge(length));
+            break;
+        }
+        file.write_all(&chunk).await?;
+        offset += chunk.len() as u64;
+    }
+    file.flush().await?;
+    drop(file);
+
+    let mut upload: partial_upload::ActiveModel = upload.into();
+    upload.expiration_time = sea_orm::ActiveValue::Set(state.partial_upload_expiration());
+    let upload = upload.update(state.db()).await?;
+    received?;
+
+    let mut res = tus_response(StatusCode::NO_CONTENT);
+    res.insert_header((UPLOAD_OFFSET_HEADER, offset.to_string()));
+    if offset < length {
+        res.insert_header((UPLOAD_EXPIRES_HEADER, http_date(upload.expiration_time)));
+    } else {
+        let finfos = state.commit_partial_upload(upload).await?;
+        insert_file_headers(&mut res, finfos);
+    }
+    Ok(res.finish())
+}
+
+#[delete("/uploads/{id}")]
+pub async fn api_view_delete_upload_id(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<impl Responder, Error> {
+    check_tus_version(&req)?;
+    let _claim = state.claim_partial_upload(&path)?;
+    let upload = state.get_partial_upload(&path).await?;
+    info!("Resumable upload {} was terminated", upload.id);
+    state.remove_partial_upload(upload).await?;
+    Ok(tus_response(StatusCode::NO_CONTENT).finish())
+}
+
+/// Name and options of a resumable upload, from its `Upload-Metadata`
+#[derive(Debug, Default)]
+struct UploadMetadata {
+    name: String,
+    options: UploadOptions,
+}
+
+impl UploadMetadata {
+    /// Parse the `Upload-Metadata` header, comma separated pairs of a key and a base64 value
+    fn from_request(req: &HttpRequest) -> Result<Self, Error> {
+        let mut metadata = Self {
+            name: DEFAULT_UPLOAD_NAME.to_string(),
+            ..Default::default()
+        };
+        let Some(raw) = req.headers().get(UPLOAD_METADATA_HEADER) else {
+            return Ok(metadata);
+        };
+        let bad_header = || Error::BadHeader(UPLOAD_METADATA_HEADER.to_string());
+        for pair in raw.to_str().map_err(|_| bad_header())?.split(',') {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let key = parts.next().unwrap_or_default();
+            let value = match parts.next() {
+                Some(encoded) => {
+                    String::from_utf8(BASE64.decode(encoded.trim()).map_err(|_| bad_header())?)?
+                }
+                None => String::new(),
+            };
+            let value = value.trim().to_string();
+            if value.is_empty() {
+                continue;
+            }
+            match key {
+                "filename" => metadata.name = file_edit::parse_name(&value)?,
+                "expires_in" => metadata.options.expires_in = Some(value),
+                "max_downloads" => metadata.options.max_downloads = Some(value),
+                "password" => metadata.options.password = Some(value),
+                "sha256" => metadata.options.sha256 = Some(value),
+                "encrypted" => metadata.options.encrypted = Some(value),
+                "slug" => metadata.options.slug = Some(value),
+                _ => debug!("Ignoring unknown upload metadata: {key}"),
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+/// Keeps other requests from working on a resumable upload until it is dropped, from
+/// [`AppState::claim_partial_upload`]
+struct UploadClaim<'a> {
+    state: &'a AppState,
+    id: String,
+}
+
+impl Drop for UploadClaim<'_> {
+    fn drop(&mut self) {
+        self.state
+            .busy_uploads
+            .lock()
+            .expect("the busy uploads are never poisoned")
+            .remove(&self.id);
+    }
+}
+
+impl AppState {
+    /// Claim the resumable upload `id` for this request
+    ///
+    /// Fails with [`Error::UploadBusy`] if another request is working on it.
+    fn claim_partial_upload(&self, id: &str) -> Result<UploadClaim<'_>, Error> {
+        if !self
+            .busy_uploads
+            .lock()
+            .expect("the busy uploads are never poisoned")
+            .insert(id.to_string())
+        {
+            return Err(Error::UploadBusy);
+        }
+        Ok(UploadClaim {
+            state: self,
+            id: id.to_string(),
+        })
+    }
+
+    fn staging_path(&self, id: &str) -> PathBuf {
+        PathBuf::from(&self.config().files.staging_dir).join(format!("{id}.part"))
+    }
+
+    /// When a partial upload that makes progress now expires
+    fn partial_upload_expiration(&self) -> NaiveDateTime {
+        chrono::Utc::now().naive_utc() + self.config().files.partial_upload_expiration.0
+    }
+
+    async fn get_partial_upload(&self, id: &str) -> Result<PartialUploadM, Error> {
+        PartialUploadE::find_by_id(id)
+            .one(self.db())
+            .await?
+            .ok_or(Error::UploadNotFound)
+    }
+
+    /// How many bytes of `upload` were received so far
+    async fn partial_upload_offset(&self, upload: &PartialUploadM) -> Result<u64, Error> {
+        Ok(tokio::fs::metadata(self.staging_path(&upload.id))
+            .await?
+            .len())
+    }
+
+    /// Turn a complete partial upload into a normal file
+    async fn commit_partial_upload(&self, upload: PartialUploadM) -> Result<FileInfos, Error> {
+        let user = match upload.user_id {
+            Some(uid) => Some(User::get_by_id(uid, self.db()).await?),
+            None => None,
+        };
+        let size = upload.length as u64;
+        let options = UploadOptions {
+            expires_in: upload.expires_in.clone(),
+            ..Default::default()
+        };
+        let mut new_file = self.new_file(user.as_ref(), &options, size)?;
+        new_file.max_downloads = upload.max_downloads.map(|n| n as u32);
+        new_file.password_hash = upload.password_hash.clone();
+        new_file.expected_sha256 = upload.sha256.clone();
+        new_file.encrypted = upload.encrypted;
+        new_file.slug = upload.slug.clone();
+        let staging_path = self.staging_path(&upload.id);
+        let hashes = self.hash_local_file(&staging_path).await?;
+        let sha256 = hashes.sha256.clone();
+        if let Err(e) = new_file.set_hashes(hashes) {
+            warn!("Resumable upload {} was corrupted: {e}", upload.id);
+            self.remove_partial_upload(upload).await?;
+            return Err(e);
+        }
+        // the storage stays reserved until the file is committed
+        let _reservation = self
+            .reserve_storage(user.as_ref(), size, Some(&sha256))
+            .await?;
+
+        let fid = self.new_fid().await?;
+        info!(
+            "Resumable upload {} is complete, storing it as {fid} in blob {sha256}",
+            upload.id
+        );
+        if let Err(e) = self
+            .commit_file(
+                fid,
+                user.as_ref(),
+                &upload.name,
+                &new_file,
+                BlobSource::Local(&staging_path),
+                Some(&upload.id),
+            )
+            .await
+        {
+            // the client can send the last PATCH again, unless that can never work
+            let moved = !tokio::fs::try_exists(&staging_path).await?;
+            if moved || matches!(e, Error::SlugTaken(_)) {
+                self.remove_partial_upload(upload).await?;
+            } else {
+                warn!("Keeping resumable upload {} to commit it again", upload.id);
+            }
+            return Err(e);
+        }
+        remove_staging_file(&staging_path).await?;
+        self.make_file_infos(fid, &upload.name).await
+    }
+
+    /// Remove the received data and the DB entry of a partial upload
+    async fn remove_partial_upload(&self, upload: PartialUploadM) -> Result<(), Error> {
+        remove_staging_file(&self.staging_path(&upload.id)).await?;
+        upload.delete(self.db()).await?;
+        Ok(())
+    }
+
+    /// Remove partial uploads that made no progress for `partial_upload_expiration`
+    pub async fn clear_expired_partial_uploads(&self) -> Result<(), Error> {
+        let now = chrono::Utc::now().naive_utc();
+        for upload in PartialUploadE::find()
+            .filter(PartialUploadC::ExpirationTime.lt(now))
+            .all(self.db())
+            .await?
+        {
+            // a slow request may still be writing to it
+            let Ok(_claim) = self.claim_partial_upload(&upload.id) else {
+                continue;
+            };
+            info!("Resumable upload was abandoned: {}", upload.id);
+            self.remove_partial_upload(upload).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn validate_staging_dir(&self) -> Result<(), Error> {
+        debug!("validate_staging_dir");
+        tokio::fs::create_dir_all(&self.config().files.staging_dir).await?;
+        Ok(())
+    }
+}
+
+/// Tell the client where the file of a finished upload is
+fn insert_file_headers(res: &mut HttpResponseBuilder, finfos: FileInfos) {
+    res.insert_header((FILE_ID_HEADER, finfos.fid.to_string()))
+        .insert_header((
+            FILE_URL_HEADER,
+            finfos.url_slug.unwrap_or(finfos.url_frontend),
+        ));
+}
+
+fn tus_response(status: StatusCode) -> HttpResponseBuilder {
+    let mut res = HttpResponse::build(status);
+    res.insert_header((TUS_RESUMABLE_HEADER, TUS_VERSION));
+    res
+}
+
+fn check_tus_version(req: &HttpRequest) -> Result<(), Error> {
+    match req.headers().get(TUS_RESUMABLE_HEADER) {
+        Some(version) if version == TUS_VERSION => Ok(()),
+        _ => Err(Error::TusVersionMismatch),
+    }
+}
+
+fn required_header<'r>(req: &'r HttpRequest, name: &str) -> Result<&'r str, Error> {
+    req.headers()
+        .get(name)
+        .ok_or_else(|| Error::MissingHeader(name.to_string()))?
+        .to_str()
+        .map_err(|e| Error::BadHeader(e.to_string()))
+}
+
+fn http_date(time: NaiveDateTime) -> String {
+    HttpDate::from(SystemTime::from(time.and_utc())).to_string()
+}
+
+/// Remove the data of a partial upload, unless it was already moved into the storage
+async fn remove_staging_file(path: &std::path::Path) -> Result<(), Error> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,361 @@
+//! Streaming uploads into the storage.
+//!
+//! Request bodies are never spooled to a temporary file. Their chunks are counted, hashed and
+//! written to the storage location of the new file as they arrive, and the upload is aborted as
+//! soon as it gets larger than allowed.
+
+use std::path::Path;
+
+use actix_multipart::{Field, Multipart};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use log::{debug, info, warn};
+use sea_orm::{EntityTrait as _, TransactionTrait as _};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::blobs::BlobSource;
+use crate::collections::MAX_COLLECTION_FILES;
+use crate::compression::StoredData;
+use crate::db::schema::collection::Model as CollectionM;
+use crate::db::schema::partial_upload::Entity as PartialUploadE;
+use crate::errors::Error;
+use crate::file_edit;
+use crate::files::{
+    DEFAULT_UPLOAD_NAME, FileHashes, FileID, FileInfos, NewFile, UPLOAD_FILE_FIELD, UploadOptions,
+};
+use crate::state::AppState;
+use crate::storage;
+use crate::user::User;
+
+/// How many chunks may wait between the request and the storage
+const CHUNK_BUFFER: usize = 8;
+/// Longest value of a form field that is not the file
+const MAX_TEXT_FIELD_LENGTH: usize = 4096;
+/// Size of the buffer for hashing files that are already stored
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// What was received and stored by [`AppState::store_upload`]
+#[derive(Debug, Clone)]
+pub struct ReceivedData {
+    pub size: u64,
+    pub hashes: FileHashes,
+}
+
+/// Computes the [`FileHashes`] of data that is passed through in chunks
+pub struct FileHasher {
+    sha256: Sha256,
+    blake3: Option<blake3::Hasher>,
+}
+
+impl FileHasher {
+    pub fn new(blake3: bool) -> Self {
+        Self {
+            sha256: Sha256::new(),
+            blake3: blake3.then(blake3::Hasher::new),
+        }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.sha256.update(chunk);
+        if let Some(blake3) = &mut self.blake3 {
+            blake3.update(chunk);
+        }
+    }
+
+    pub fn finalize(self) -> FileHashes {
+        FileHashes {
+            sha256: format!("{:x}", self.sha256.finalize()),
+            blake3: self.blake3.map(|h| h.finalize().to_hex().to_string()),
+        }
+    }
+}
+
+impl AppState {
+    pub fn file_hasher(&self) -> FileHasher {
+        FileHasher::new(self.config().files.blake3)
+    }
+
+    /// Hash the object `key` of the storage
+    pub async fn hash_stored(&self, key: &str) -> Result<FileHashes, Error> {
+        let mut hasher = self.file_hasher();
+        let mut data = self.storage().stream(key, None).await?;
+        while let Some(chunk) = data.try_next().await? {
+            hasher.update(&chunk);
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Hash the original data of a file or blob, decompressed and decrypted
+    pub async fn hash_data(&self, data: &StoredData) -> Result<FileHashes, Error> {
+        let mut hasher = self.file_hasher();
+        let mut stream = self.stream_data(data, None).await?;
+        while let Some(chunk) = stream.try_next().await? {
+            hasher.update(&chunk);
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Hash a file on the local filesystem
+    pub async fn hash_local_file(&self, path: &Path) -> Result<FileHashes, Error> {
+        let mut hasher = self.file_hasher();
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut buf = vec![0; HASH_BUFFER_SIZE];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finalize())
+    }
+}
+
+impl AppState {
+    /// Store everything from `body` as the staged upload of `fid`
+    ///
+    /// Fails with [`Error::UploadTooLarge`] once `body` is longer than `limit`. If storing
+    /// fails, nothing is left in the storage for `fid`.
+    pub async fn store_upload<S, E>(
+        &self,
+        fid: FileID,
+        mut body: S,
+        limit: u64,
+    ) -> Result<ReceivedData, Error>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        Error: From<E>,
+    {
+        let key = storage::key_staged_upload(fid);
+        debug!("Streaming upload to {key}, at most {limit} bytes");
+
+        // request bodies can't be sent between threads, so the chunks are passed on to the
+        // storage through a channel, by a future running alongside the storage
+        let (tx, rx) = tokio::sync::mpsc::channel::<Result<Bytes, Error>>(CHUNK_BUFFER);
+        let mut hasher = self.file_hasher();
+        let receive = async move {
+            let mut size: u64 = 0;
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk.map_err(Error::from).and_then(|chunk| {
+                    size += chunk.len() as u64;
+                    if size > limit {
+                        Err(Error::UploadTooLarge(limit))
+                    } else {
+                        hasher.update(&chunk);
+                        Ok(chunk)
+                    }
+                });
+                let failed = chunk.is_err();
+                if tx.send(chunk).await.is_err() || failed {
+                    break;
+                }
+            }
+            ReceivedData {
+                size,
+                hashes: hasher.finalize(),
+            }
+        };
+        let chunks = futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|chunk| (chunk, rx))
+        })
+        .boxed();
+
+        let (received, stored) =
+            futures_util::future::join(receive, self.storage().put_stream(&key, chunks)).await;
+        if let Err(e) = stored {
+            warn!("Error while uploading file: {e}");
+            self.storage()
+                .delete_all(&storage::key_staging(fid))
+                .await?;
+            return Err(e);
+        }
+
+        debug!("Stored upload {key}: {received:?}");
+        Ok(received)
+    }
+}
+
+impl AppState {
+    /// Receive a multipart upload form, streaming its files into the storage as new files
+    ///
+    /// The files can be at most `limit` bytes large together, and there can be at most
+    /// [`MAX_COLLECTION_FILES`] of them. The other fields of the form are put into `options`.
+    /// Returns the id and name of each new file and what was received, the files do not have
+    /// DB entries yet. If anything fails, nothing is left in the storage.
+    pub async fn receive_upload_form(
+        &self,
+        mut form: Multipart,
+        limit: u64,
+        options: &mut UploadOptions,
+    ) -> Result<Vec<(FileID, String, ReceivedData)>, Error> {
+        let mut stored = Vec::new();
+        let received: Result<(), Error> = async {
+            while let Some(mut field) = form.try_next().await? {
+                let field_name = field.name().unwrap_or_default().to_string();
+                if field_name == UPLOAD_FILE_FIELD {
+                    if stored.len() >= MAX_COLLECTION_FILES {
+                        return Err(Error::TooManyFiles(MAX_COLLECTION_FILES));
+                    }
+                    let name = match field
+                        .content_disposition()
+                        .and_then(|cd| cd.get_filename())
+                        .filter(|name| !name.trim().is_empty())
+                    {
+                        Some(name) => file_edit::parse_name(name)?,
+                        None => DEFAULT_UPLOAD_NAME.to_string(),
+                    };
+                    let used: u64 = stored
+                        .iter()
+                        .map(|(_, _, data): &(FileID, String, ReceivedData)| data.size)
+                        .sum();
+                    let fid = self.new_fid().await?;
+                    let data = self
+                        .store_upload(fid, &mut field, limit - used)
+                        .await
+                        .map_err(|e| match e {
+                            // the limit is for all files together
+                            Error::UploadTooLarge(_) => Error::UploadTooLarge(limit),
+                            e => e,
+                        })?;
+                    stored.push((fid, name, data));
+                } else {
+                    let value = read_text_field(&mut field).await?;
+                    if !options.set_field(&field_name, &value) {
+                        debug!("Ignoring unknown upload field: {field_name}");
+                    }
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        match received {
+            Ok(()) if stored.is_empty() => {
+                Err(Error::MissingFormField(UPLOAD_FILE_FIELD.to_string()))
+            }
+            Ok(()) => Ok(stored),
+            Err(e) => {
+                self.discard_uploads(&stored).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Create the file for an upload that was stored with [`store_upload`](Self::store_upload)
+    ///
+    /// If the `options` are bad, the data does not have the expected checksum or the storage
+    /// quota does not allow the upload, the stored data is removed again. A file that is part of
+    /// a `collection` expires together with it.
+    pub async fn commit_upload(
+        &self,
+        fid: FileID,
+        name: &str,
+        user: Option<&User>,
+        options: &UploadOptions,
+        received: &ReceivedData,
+        collection: Option<&CollectionM>,
+    ) -> Result<FileInfos, Error> {
+        let checked = match self
+            .new_file(user, options, received.size)
+            .and_then(|mut new_file| {
+                if let Some(collection) = collection {
+                    new_file.expiration = collection.expiration_time;
+                    new_file.collection = Some(collection.id.clone());
+                }
+                new_file
+                    .set_hashes(received.hashes.clone())
+                    .map(|()| new_file)
+            }) {
+            Ok(new_file) => self
+                .reserve_storage(user, received.size, Some(&received.hashes.sha256))
+                .await
+                .map(|reservation| (new_file, reservation)),
+            Err(e) => Err(e),
+        };
+        // the storage stays reserved until the file is committed
+        let (new_file, _reservation) = match checked {
+            Ok(checked) => checked,
+            Err(e) => {
+                self.storage()
+                    .delete_all(&storage::key_staging(fid))
+                    .await?;
+                return Err(e);
+            }
+        };
+
+        let sha256 = &received.hashes.sha256;
+        info!(
+            "Upload of {fid} is complete: {} bytes, sha256 {sha256}",
+            received.size
+        );
+        let key = storage::key_staged_upload(fid);
+        let committed = self
+            .commit_file(fid, user, name, &new_file, BlobSource::Stored(&key), None)
+            .await;
+        self.storage()
+            .delete_all(&storage::key_staging(fid))
+            .await?;
+        committed?;
+        self.make_file_infos(fid, name).await
+    }
+}
+
+impl AppState {
+    /// Store the data of a new file from `source` in its blob and create the file
+    ///
+    /// The file and the reference to its blob are added to the DB in one transaction, together
+    /// with removing the resumable upload `partial_upload` that the file comes from. If
+    /// anything fails, the file does not exist. `source` may be moved into the blob, whatever
+    /// is left of it is up to the caller.
+    pub async fn commit_file(
+        &self,
+        fid: FileID,
+        user: Option<&User>,
+        name: &str,
+        new_file: &NewFile,
+        source: BlobSource<'_>,
+        partial_upload: Option<&str>,
+    ) -> Result<(), Error> {
+        let sha256 = &new_file
+            .hashes
+            .as_ref()
+            .expect("the hashes of a new file are set before committing it")
+            .sha256;
+        // `source` may be gone once the blob is prepared
+        let content_type = self.source_content_type(source).await?;
+        let blob = self.prepare_blob(sha256, new_file.size, source).await?;
+        let committed: Result<(), Error> = async {
+            let txn = self.db().begin().await?;
+            blob.reference(&txn).await?;
+            self.create_file_db_entry(fid, user, name, &content_type, new_file, &txn)
+                .await?;
+            if let Some(id) = partial_upload {
+                PartialUploadE::delete_by_id(id).exec(&txn).await?;
+            }
+            txn.commit().await?;
+            Ok(())
+        }
+        .await;
+        if let Err(e) = committed {
+            warn!("Could not commit file {fid}, rolling back: {e}");
+            self.discard_blob(blob).await?;
+            return Err(e);
+        }
+        debug!("Committed file {fid}");
+        self.queue_thumbnails(fid);
+        Ok(())
+    }
+}
+
+/// Read a small form field as text
+async fn read_text_field(field: &mut Field) -> Result<String, Error> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.try_next().await? {
+        if value.len() + chunk.len() > MAX_TEXT_FIELD_LENGTH {
+            return Err(Error::UploadTooLarge(MAX_TEXT_FIELD_LENGTH as u64));
+        }
+        value.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8(value)?)
+}
@@ -5,6 +5,7 @@ use log::error;
 
 use crate::files::FileID;
 use crate::state::AppState;
+use crate::thumbnails::ThumbnailSize;
 
 /// WARNING: DO NOT PUT STUFF INSIDE THE BRACKETS LIKE SIMPLE FORMAT SYNTAX. THESE ITEMS WILL NOT
 /// BE URLENCODED!
@@ -36,6 +37,46 @@ impl AppState {
         self.uri_any(&uri_any!("/api/v1/file/{}/{}", fid, name))
     }
 
+    pub fn uri_api_collection_id(&self, id: &str) -> Uri {
+        self.uri_any(&uri_any!("/api/v1/collection/{}", id))
+    }
+
+    pub fn uri_api_collection_id_archive(&self, id: &str) -> Uri {
+        self.uri_any(&uri_any!("/api/v1/collection/{}/archive", id))
+    }
+
+    /// The segments of `path` are encoded on their own, so that its slashes stay
+    pub fn uri_api_file_fid_name_entry(&self, fid: FileID, name: &str, path: &str) -> Uri {
+        let path: Vec<_> = path.split('/').map(urlencoding::encode).collect();
+        self.uri_any(&format!(
+            "{}/entry/{}",
+            uri_any!("/api/v1/file/{}/{}", fid, name),
+            path.join("/")
+        ))
+    }
+
+    pub fn uri_api_file_fid_name_thumbnail(
+        &self,
+        fid: FileID,
+        name: &str,
+        size: ThumbnailSize,
+    ) -> Uri {
+        self.uri_any(&uri_any!(
+            "/api/v1/file/{}/{}/thumbnail?size={}",
+            fid,
+            name,
+            size.name()
+        ))
+    }
+
+    pub fn uri_api_uploads(&self) -> Uri {
+        self.uri_any(&uri_any!("/api/v1/uploads"))
+    }
+
+    pub fn uri_api_upload_id(&self, id: &str) -> Uri {
+        self.uri_any(&uri_any!("/api/v1/uploads/{}", id))
+    }
+
     pub fn base_uri(&self) -> Uri {
         Uri::from_str(&self.config.service.base_url)
             .expect("base_url of config was not a proper url")
@@ -61,6 +102,18 @@ impl AppState {
         self.uri_any(&uri_any!("/file/{}/{}", fid, name))
     }
 
+    pub fn uri_frontend_file_slug(&self, slug: &str) -> Uri {
+        self.uri_any(&uri_any!("/file/{}", slug))
+    }
+
+    pub fn uri_frontend_collection_id(&self, id: &str) -> Uri {
+        self.uri_any(&uri_any!("/collection/{}", id))
+    }
+
+    pub fn uri_frontend_unlock_fid(&self, fid: FileID) -> Uri {
+        self.uri_any(&uri_any!("/unlock/{}", fid))
+    }
+
     pub fn uri_frontend_index(&self) -> Uri {
         self.uri_any(&uri_any!("/"))
     }
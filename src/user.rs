@@ -246,7 +246,9 @@ impl User {
         argon2::Argon2::default()
     }
 
-    fn load_password_hash(stored_hash: &str) -> Result<argon2::password_hash::PasswordHash, Error> {
+    pub(crate) fn load_password_hash(
+        stored_hash: &str,
+    ) -> Result<argon2::password_hash::PasswordHash<'_>, Error> {
         match PasswordHash::parse(stored_hash, HASH_ENCODING) {
             Ok(hash) => Ok(hash),
             Err(e) => {
@@ -260,7 +262,7 @@ impl User {
         }
     }
 
-    fn hash_password<'t>(
+    pub(crate) fn hash_password<'t>(
         cleartext: &str,
         salt: argon2::password_hash::Salt<'t>,
     ) -> Result<argon2::password_hash::PasswordHash<'t>, Error> {
@@ -1,22 +1,25 @@
-use std::io::Read;
 use std::str::FromStr;
 
 use actix_identity::Identity;
+use actix_session::Session;
 use actix_web::body::BoxBody;
+use actix_web::http::header;
 use actix_web::web::Either;
 use actix_web::web::Redirect;
 use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError, get, post, web};
 use argon2::password_hash::SaltString;
 use chrono::Datelike;
-use log::trace;
+use log::{trace, warn};
 use minijinja::context;
 use serde::Serialize;
 
+use crate::archive_contents::ArchiveKind;
 use crate::auth::{AuthUser, MaybeAuthUser};
-use crate::config::Config;
+use crate::config::{Config, HumanDuration};
 use crate::db::schema::user_token::Model as UserTokenM;
 use crate::errors::{Error, ErrorPageDetails};
 use crate::files::FileID;
+use crate::protection::{self, UnlockForm};
 use crate::state::AppState;
 use crate::user::{self, User, UserLoginData, UserLoginDataWeb, UserRegisterData};
 
@@ -97,29 +100,49 @@ macro_rules! ok {
     };
 }
 
+/// Expirations that can be picked in the upload form, if the user is allowed to
+const EXPIRATION_CHOICES: &[(&str, &str)] = &[
+    ("10m", "10 minutes"),
+    ("1h", "1 hour"),
+    ("1d", "1 day"),
+    ("7d", "7 days"),
+    ("30d", "30 days"),
+    ("60d", "60 days"),
+    ("365d", "1 year"),
+];
+
+/// The [`EXPIRATION_CHOICES`] that `user` may choose
+fn expiration_choices(
+    state: &AppState,
+    user: Option<&User>,
+) -> Result<Vec<(&'static str, &'static str)>, Error> {
+    let limits = state.expiration_limits(user)?;
+    Ok(EXPIRATION_CHOICES
+        .iter()
+        .filter(|(value, _)| {
+            HumanDuration::from_str(value).is_ok_and(|duration| limits.contains(duration))
+        })
+        .copied()
+        .collect())
+}
+
 async fn frontend_view_inner_index(
     state: &web::Data<AppState>,
     identity: MaybeAuthUser,
 ) -> Result<HttpResponse<BoxBody>, Error> {
     let user: Option<User> = identity.user();
+    let limits = state.expiration_limits(user.as_ref())?;
+    let default_expiration = limits.clamp(HumanDuration(state.get_expiration_offset()));
+    let expiration_choices = expiration_choices(state, user.as_ref())?;
 
     let content: String = state
         .templating()?
         .get_template("index.html")?
-        .render(context!(bctx => BasicContext::build(state, user).await?))?;
-    Ok(HttpResponse::Ok().body(content))
-}
-
-async fn frontend_view_error(
-    state: web::Data<AppState>,
-    error: Error,
-    identity: MaybeAuthUser,
-) -> Result<HttpResponse<BoxBody>, Error> {
-    let user = identity.user();
-    let error_details = ErrorPageDetails::from(error);
-    let content: String = state.templating()?.get_template("error.html")?.render(
-        context!(bctx => BasicContext::build(&state, user).await?, error => error_details),
-    )?;
+        .render(context!(
+            bctx => BasicContext::build(state, user).await?,
+            expiration_choices => expiration_choices,
+            default_expiration => default_expiration,
+        ))?;
     Ok(HttpResponse::Ok().body(content))
 }
 
@@ -174,11 +197,24 @@ pub async fn frontend_view_get_about(
 pub async fn frontend_view_get_file_fid(
     state: web::Data<AppState>,
     path: web::Path<String>,
+    session: Session,
     identity: MaybeAuthUser,
 ) -> Result<impl Responder, Error> {
     handle_frontend_error!(state, identity, {
-        let fid: crate::files::FileID = FileID::from_str(&path.into_inner())?;
-        let name = state.get_filename_for_fid(fid)?;
+        let fid = state.resolve_fid(&path.into_inner()).await?;
+        match state
+            .check_file_access(fid, None, &session, identity.user_ref())
+            .await
+        {
+            Err(Error::FilePasswordRequired) => {
+                return ok!(
+                    Redirect,
+                    Redirect::to(state.uri_frontend_unlock_fid(fid).to_string())
+                );
+            }
+            other => other?,
+        }
+        let name = state.get_filename_for_fid(fid).await?;
 
         trace!("done with body");
         ok!(
@@ -192,27 +228,68 @@ pub async fn frontend_view_get_file_fid(
 pub async fn frontend_view_get_file_fid_name(
     state: web::Data<AppState>,
     identity: MaybeAuthUser,
+    session: Session,
     urlpath: web::Path<(String, String)>,
 ) -> Result<impl Responder, Error> {
     handle_frontend_error!(state, identity, {
         let user = identity.user();
 
         let urlargs = urlpath.into_inner();
-        let fid = FileID::from_str(&urlargs.0)?;
+        let fid = state.resolve_fid(&urlargs.0).await?;
+        match state
+            .check_file_access(fid, None, &session, user.as_ref())
+            .await
+        {
+            Err(Error::FilePasswordRequired) => {
+                return ok!(HttpResponse::TemporaryRedirect()
+                    .insert_header((
+                        header::LOCATION,
+                        state.uri_frontend_unlock_fid(fid).to_string()
+                    ))
+                    .finish());
+            }
+            other => other?,
+        }
         let name = urlargs.1;
         let finfo = state.make_file_infos(fid, &name).await?;
-        let ct = finfo.content_type()?;
-
-        const MAX_PREVIEW_LENGTH: usize = 16384;
-        let mut file_content_preview = vec![0; MAX_PREVIEW_LENGTH];
-        let mut file = std::fs::File::open(state.upload_datafile_for_fid(fid, &name, false)?)?;
-        #[allow(clippy::unused_io_amount)] // TODO: maybe need to handle partial reads
-        file.read(&mut file_content_preview)?;
-        let mut text_content: String = String::from_utf8_lossy(&file_content_preview).to_string();
-        if file_content_preview.len() < finfo.size as usize {
-            text_content.push_str("\n===============\n(abbreviated)");
+        // encrypted files are previewed by the browser, which only has the name to go by
+        let ct = match std::path::Path::new(&name).extension() {
+            Some(ext) if finfo.encrypted => {
+                actix_files::file_extension_to_mime(&ext.to_string_lossy())
+            }
+            _ => finfo.content_type()?,
+        };
+
+        // showing the content of a file with a download limit would bypass the limit
+        let show_content = finfo.downloads_remaining.is_none();
+        const MAX_PREVIEW_LENGTH: u64 = 16384;
+        // archives list their entries instead of showing their binary data
+        let is_archive = !finfo.encrypted && ArchiveKind::from_name(&name).is_some();
+        let (mut archive_entries, mut archive_error) = (None, None);
+        if show_content && is_archive {
+            match state.archive_entries(fid, &name).await {
+                Ok(entries) => archive_entries = Some(entries),
+                Err(e) => {
+                    warn!("Could not list the entries of archive {fid}: {e}");
+                    archive_error = Some(e.to_string());
+                }
+            }
+        }
+        let mut text_content = String::new();
+        if show_content && !finfo.encrypted && !is_archive {
+            let file_content_preview = state
+                .read_data(
+                    &state.stored_data(fid, &name).await?,
+                    Some(0..MAX_PREVIEW_LENGTH),
+                )
+                .await?;
+            text_content = String::from_utf8_lossy(&file_content_preview).to_string();
+            if (file_content_preview.len() as u64) < finfo.size {
+                text_content.push_str("\n===============\n(abbreviated)");
+            }
         }
         let owns_this_file = user.is_some() && finfo.uploader.is_some() && user == finfo.uploader;
+        let expiration_choices = expiration_choices(&state, user.as_ref())?;
 
         let content: String =
             state
@@ -224,12 +301,77 @@ pub async fn frontend_view_get_file_fid_name(
                     content_type_general => ct.type_().to_string(),
                     content_type_full => ct.to_string(),
                     file_content => text_content,
+                    show_content => show_content,
                     owns_this_file => owns_this_file,
+                    expiration_choices => expiration_choices,
+                    archive_entries => archive_entries,
+                    archive_error => archive_error,
                 ))?;
         ok!(HttpResponse::Ok().body(content))
     })
 }
 
+#[get("/unlock/{fid}")]
+pub async fn frontend_view_get_unlock_fid(
+    state: web::Data<AppState>,
+    identity: MaybeAuthUser,
+    path: web::Path<String>,
+) -> Result<impl Responder, Error> {
+    handle_frontend_error!(state, identity, {
+        let fid = state.resolve_fid(&path.into_inner()).await?;
+        render_unlock(&state, identity.user(), fid, None).await
+    })
+}
+
+#[post("/unlock/{fid}")]
+pub async fn frontend_view_post_unlock_fid(
+    state: web::Data<AppState>,
+    identity: MaybeAuthUser,
+    session: Session,
+    path: web::Path<String>,
+    web::Form(unlock): web::Form<UnlockForm>,
+) -> Result<impl Responder, Error> {
+    handle_frontend_error!(state, identity, {
+        let user = identity.user();
+        let fid = state.resolve_fid(&path.into_inner()).await?;
+        match state
+            .check_file_access(fid, Some(unlock.password.trim()), &session, user.as_ref())
+            .await
+        {
+            Err(e @ Error::WrongFilePassword) => {
+                return render_unlock(&state, user, fid, Some(e.to_string())).await;
+            }
+            other => other?,
+        }
+        protection::unlock_in_session(&session, fid)?;
+        ok!(HttpResponse::SeeOther()
+            .insert_header((
+                header::LOCATION,
+                state.uri_frontend_file_fid(fid).to_string()
+            ))
+            .finish())
+    })
+}
+
+/// The form to enter the password of a protected file
+async fn render_unlock(
+    state: &web::Data<AppState>,
+    user: Option<User>,
+    fid: FileID,
+    error: Option<String>,
+) -> Result<HttpResponse, Error> {
+    let content: String = state
+        .templating()?
+        .get_template("unlock.html")?
+        .render(context!(
+            bctx => BasicContext::build(state, user).await?,
+            fid => fid,
+            url_unlock => state.uri_frontend_unlock_fid(fid).to_string(),
+            error => error,
+        ))?;
+    Ok(HttpResponse::Unauthorized().body(content))
+}
+
 pub async fn view_default(
     state: web::Data<AppState>,
     identity: MaybeAuthUser,
@@ -358,10 +500,65 @@ pub async fn frontend_view_get_settings(
     let user = identity.user();
     handle_frontend_error!(state, Some(user.clone()), {
         let tokens: Vec<UserTokenM> = user.tokens(state.db()).await?;
+        let storage_usage = state.storage_usage_user(&user).await?;
 
-        let content: String = state.templating()?.get_template("settings.html")?.render(
-            context!(bctx => BasicContext::build(&state, Some(user)).await?, tokens => tokens),
-        )?;
+        let content: String =
+            state
+                .templating()?
+                .get_template("settings.html")?
+                .render(context!(
+                    bctx => BasicContext::build(&state, Some(user)).await?,
+                    tokens => tokens,
+                    storage_usage => storage_usage,
+                ))?;
+        ok!(HttpResponse::Ok().body(content))
+    })
+}
+
+#[get("/files")]
+pub async fn frontend_view_get_files(
+    state: web::Data<AppState>,
+    identity: AuthUser,
+) -> Result<impl Responder, Error> {
+    let user = identity.user();
+    handle_frontend_error!(state, Some(user.clone()), {
+        let expiration_choices = expiration_choices(&state, Some(&user))?;
+
+        let content: String = state
+            .templating()?
+            .get_template("files.html")?
+            .render(context!(
+                bctx => BasicContext::build(&state, Some(user)).await?,
+                expiration_choices => expiration_choices,
+            ))?;
+        ok!(HttpResponse::Ok().body(content))
+    })
+}
+
+#[get("/collection/{id}")]
+pub async fn frontend_view_get_collection_id(
+    state: web::Data<AppState>,
+    identity: MaybeAuthUser,
+    session: Session,
+    path: web::Path<String>,
+) -> Result<impl Responder, Error> {
+    handle_frontend_error!(state, identity, {
+        let user = identity.user();
+        let collection = state.get_collection(&path.into_inner()).await?;
+        let cinfos = state
+            .make_collection_infos(collection, None, &session, user.as_ref())
+            .await?;
+        let owns_this_collection =
+            user.is_some() && cinfos.uploader.is_some() && user == cinfos.uploader;
+
+        let content: String = state
+            .templating()?
+            .get_template("collection.html")?
+            .render(context!(
+                bctx => BasicContext::build(&state, user).await?,
+                cinfos => cinfos,
+                owns_this_collection => owns_this_collection,
+            ))?;
         ok!(HttpResponse::Ok().body(content))
     })
 }